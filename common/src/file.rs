@@ -1,5 +1,6 @@
 use anyhow::Ok;
 use aws_sdk_s3::primitives::ByteStream;
+use sha2::{Digest, Sha256};
 use std::fs;
 use std::io::Read;
 use std::io::Write;
@@ -7,6 +8,81 @@ use std::thread;
 use tokio::io::AsyncReadExt;
 use tokio::runtime::Runtime;
 
+/// Written into the segment dir by the executor, holds the number of segments split so far
+pub const SEGMENT_MANIFEST: &str = "manifest";
+
+/// Written next to every segment and receipt, holds the hex sha256 of its content
+pub const CHECKSUM_SUFFIX: &str = ".sha256";
+
+/// Start of the error of an artifact that does not match its checksum, the path follows
+pub const CORRUPT_ARTIFACT: &str = "corrupt artifact";
+
+#[derive(Debug)]
+pub struct CorruptArtifact {
+    pub path: String,
+}
+
+impl std::fmt::Display for CorruptArtifact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", CORRUPT_ARTIFACT, self.path)
+    }
+}
+
+impl std::error::Error for CorruptArtifact {}
+
+/// Path of the corrupt artifact a task failed on, from its error message
+pub fn corrupt_artifact_path(errmsg: &str) -> Option<&str> {
+    errmsg
+        .strip_prefix(CORRUPT_ARTIFACT)?
+        .strip_prefix(' ')
+        .filter(|path| !path.is_empty())
+}
+
+fn checksum(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Writer of an artifact that records its checksum, read it back with `read_checked`. The
+/// artifact and its checksum are written once, on `close` or when the writer is dropped.
+pub fn new_checked(path: &str) -> CheckedFile {
+    CheckedFile {
+        path: path.to_string(),
+        data: Vec::new(),
+        closed: false,
+    }
+}
+
+/// Write a whole artifact together with its checksum
+pub fn write_checked(path: &str, data: &[u8]) -> anyhow::Result<()> {
+    let mut file = new_checked(path);
+    file.write_all(data)?;
+    file.close()?;
+    Ok(())
+}
+
+/// Record the checksum of an artifact another library has already written
+pub fn record_checksum(path: &str) -> anyhow::Result<()> {
+    let data = new(path).read()?;
+    write_checked(path, &data)
+}
+
+/// Read an artifact and check it against the checksum written with it. Artifacts without
+/// a checksum, written before checksums were recorded, are returned as they are.
+pub fn read_checked(path: &str) -> anyhow::Result<Vec<u8>> {
+    let data = new(path).read()?;
+    let expected = match new(&format!("{}{}", path, CHECKSUM_SUFFIX)).read_to_string() {
+        std::result::Result::Ok(expected) => expected,
+        Err(_) => return Ok(data),
+    };
+    if expected.trim() != checksum(&data) {
+        return Err(CorruptArtifact {
+            path: path.to_string(),
+        }
+        .into());
+    }
+    Ok(data)
+}
+
 pub fn new(path: &str) -> Box<dyn File> {
     if is_s3_path(path) {
         return Box::new(S3File::new(path));
@@ -25,6 +101,51 @@ pub trait File: std::io::Write {
     fn create_dir_all(&self) -> anyhow::Result<()>;
 }
 
+pub struct CheckedFile {
+    path: String,
+    // every write of a File replaces its content, so the artifact is kept until close
+    data: Vec<u8>,
+    closed: bool,
+}
+
+impl CheckedFile {
+    /// Write the artifact, then the checksum of its whole content
+    pub fn close(mut self) -> std::io::Result<()> {
+        self.commit()
+    }
+
+    fn commit(&mut self) -> std::io::Result<()> {
+        self.closed = true;
+        let _ = new(&self.path).write(&self.data)?;
+        let _ = new(&format!("{}{}", self.path, CHECKSUM_SUFFIX))
+            .write(checksum(&self.data).as_bytes())?;
+        std::result::Result::Ok(())
+    }
+}
+
+impl Write for CheckedFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        std::result::Result::Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::result::Result::Ok(())
+    }
+}
+
+// writers handed to other libraries are dropped without close
+impl Drop for CheckedFile {
+    fn drop(&mut self) {
+        if self.closed {
+            return;
+        }
+        if let Err(e) = self.commit() {
+            log::error!("write {} error: {:?}", self.path, e);
+        }
+    }
+}
+
 pub struct LocalFile {
     pub path: String,
 }
@@ -109,7 +230,7 @@ impl Write for S3File {
         handle
             .join()
             .unwrap()
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, format!("{}", e)))?;
+            .map_err(|e| std::io::Error::other(format!("{}", e)))?;
         std::result::Result::Ok(length)
     }
 
@@ -254,3 +375,37 @@ async fn get_s3_client() -> aws_sdk_s3::Client {
     let config = aws_config::load_from_env().await;
     aws_sdk_s3::Client::new(&config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checked_file() {
+        let dir = std::env::temp_dir().join(format!("checked_file_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("receipt").to_str().unwrap().to_string();
+
+        // the checksum covers every write, not only the last one
+        let mut checked = new_checked(&path);
+        checked.write_all(b"first ").unwrap();
+        checked.write_all(b"second").unwrap();
+        checked.close().unwrap();
+        assert_eq!(read_checked(&path).unwrap(), b"first second");
+
+        // a writer dropped without close still writes the artifact
+        {
+            let mut checked = new_checked(&path);
+            checked.write_all(b"dropped").unwrap();
+        }
+        assert_eq!(read_checked(&path).unwrap(), b"dropped");
+
+        let _ = new(&path).write(b"tampered").unwrap();
+        let err = read_checked(&path).unwrap_err();
+        assert!(err.downcast_ref::<CorruptArtifact>().is_some());
+
+        record_checksum(&path).unwrap();
+        assert_eq!(read_checked(&path).unwrap(), b"tampered");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
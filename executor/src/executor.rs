@@ -2,16 +2,34 @@ use crate::split_context::SplitContext;
 use common::file;
 use elf::{endian::AnyEndian, ElfBytes};
 use num::ToPrimitive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use zkm_emulator::state::{InstrumentedState, State};
 use zkm_emulator::utils::get_block_path;
 
 #[derive(Default)]
-pub struct Executor {}
+pub struct Executor {
+    cancelled: Option<Arc<AtomicBool>>,
+}
 
 impl Executor {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Stop splitting at the next segment boundary once `cancelled` is set
+    pub fn with_cancel_flag(cancelled: Arc<AtomicBool>) -> Self {
+        Executor {
+            cancelled: Some(cancelled),
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        match &self.cancelled {
+            Some(cancelled) => cancelled.load(Ordering::Relaxed),
+            None => false,
+        }
+    }
 }
 
 impl Executor {
@@ -92,8 +110,9 @@ impl Executor {
                     let new_write = |_: &str| -> Option<std::fs::File> { None };
                     instrumented_state.split_segment(false, &seg_path_clone, new_write);
 
-                    let new_write =
-                        |name: &str| -> Option<Box<dyn std::io::Write>> { Some(file::new(name)) };
+                    let new_write = |name: &str| -> Option<Box<dyn std::io::Write>> {
+                        Some(Box::new(file::new_checked(name)))
+                    };
                     let mut loop_index = 0;
                     loop {
                         if instrumented_state.state.exited {
@@ -108,9 +127,15 @@ impl Executor {
                         if cycles >= split_seg_size {
                             instrumented_state.split_segment(true, &seg_path_clone, new_write);
                             loop_index += 1;
+                            write_manifest(&seg_path_clone, loop_index);
+                            if self.is_cancelled() {
+                                log::info!("split {} cancelled", elf_path);
+                                return Err("cancelled".to_string());
+                            }
                         }
                     }
                     instrumented_state.split_segment(true, &seg_path_clone, new_write);
+                    write_manifest(&seg_path_clone, loop_index + 1);
                     log::info!(
                         "Split done {} : {}",
                         instrumented_state.state.total_step,
@@ -132,3 +157,11 @@ impl Executor {
         Ok(0)
     }
 }
+
+/// Announce the segments written so far, so the stage can prove them while splitting goes on
+fn write_manifest(seg_path: &str, segments: usize) {
+    let manifest = format!("{}/{}", seg_path, file::SEGMENT_MANIFEST);
+    if let Err(e) = file::new(&manifest).write(segments.to_string().as_bytes()) {
+        log::warn!("split write manifest {} failed {}", manifest, e);
+    }
+}
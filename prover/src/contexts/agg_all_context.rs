@@ -8,9 +8,14 @@ pub struct AggAllContext {
     pub proof_num: u32,
     pub receipt_dir: String,
     pub output_dir: String,
+    // write the aggregate receipt here and leave wrapping to a wrap job, wrap into
+    // output_dir when empty
+    #[serde(default)]
+    pub agg_receipt_path: String,
 }
 
 impl AggAllContext {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         basedir: &String,
         block_no: u64,
@@ -18,6 +23,7 @@ impl AggAllContext {
         proof_num: u32,
         receipt_dir: &String,
         output_dir: &String,
+        agg_receipt_path: &String,
     ) -> Self {
         AggAllContext {
             basedir: basedir.to_string(),
@@ -26,6 +32,7 @@ impl AggAllContext {
             proof_num,
             receipt_dir: receipt_dir.to_string(),
             output_dir: output_dir.to_string(),
+            agg_receipt_path: agg_receipt_path.to_string(),
         }
     }
 }
@@ -6,3 +6,6 @@ pub use agg_context::AggContext;
 
 pub mod agg_all_context;
 pub use agg_all_context::AggAllContext;
+
+pub mod wrap_context;
+pub use wrap_context::WrapContext;
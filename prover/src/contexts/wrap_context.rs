@@ -0,0 +1,28 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct WrapContext {
+    pub basedir: String,
+    pub block_no: u64,
+    pub seg_size: u32,
+    pub agg_receipt_path: String,
+    pub output_dir: String,
+}
+
+impl WrapContext {
+    pub fn new(
+        basedir: &String,
+        block_no: u64,
+        seg_size: u32,
+        agg_receipt_path: &String,
+        output_dir: &String,
+    ) -> Self {
+        WrapContext {
+            basedir: basedir.to_string(),
+            block_no,
+            seg_size,
+            agg_receipt_path: agg_receipt_path.to_string(),
+            output_dir: output_dir.to_string(),
+        }
+    }
+}
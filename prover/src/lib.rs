@@ -1,3 +1,4 @@
 pub mod contexts;
 pub mod pipeline;
 pub mod provers;
+pub mod receipt;
@@ -1,21 +1,27 @@
-use crate::contexts::{AggAllContext, AggContext, ProveContext};
-use crate::provers::{AggAllProver, AggProver, Prover, RootProver};
+use crate::contexts::{AggAllContext, AggContext, ProveContext, WrapContext};
+use crate::provers::{AggAllProver, AggProver, Prover, RootProver, WrapProver};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 // use anyhow::{anyhow, bail, Result};
 // use std::path::Path;
-use std::sync::Mutex;
 
+/// Runs the provers. Jobs are not serialized here, the prover node limits how many
+/// of each type run at once.
 #[derive(Debug, Default)]
 pub struct Pipeline {
-    _mutex: Mutex<usize>,
+    cancelled: Option<Arc<AtomicBool>>,
 }
 
-static PIPELINE_MUTEX: Mutex<usize> = Mutex::new(0);
-
 impl Pipeline {
     pub fn new() -> Self {
+        Pipeline { cancelled: None }
+    }
+
+    /// Stop the provers between their stages once `cancelled` is set
+    pub fn with_cancel_flag(cancelled: Arc<AtomicBool>) -> Self {
         Pipeline {
-            _mutex: Mutex::new(0),
+            cancelled: Some(cancelled),
         }
     }
 
@@ -23,18 +29,15 @@ impl Pipeline {
         &mut self,
         prove_context: &ProveContext,
     ) -> std::result::Result<bool, String> {
-        let result = PIPELINE_MUTEX.try_lock();
-        match result {
-            Ok(_) => match RootProver::new().prove(prove_context) {
-                Ok(()) => Ok(true),
-                Err(e) => {
-                    log::error!("prove_root error {:#?}", e);
-                    Err(e.to_string())
-                }
-            },
-            Err(_e) => {
-                log::error!("prove_root busy");
-                Ok(false)
+        let prover = match &self.cancelled {
+            Some(cancelled) => RootProver::with_cancel_flag(cancelled.clone()),
+            None => RootProver::new(),
+        };
+        match prover.prove(prove_context) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                log::error!("prove_root error {:#?}", e);
+                Err(e.to_string())
             }
         }
     }
@@ -43,18 +46,15 @@ impl Pipeline {
         &mut self,
         agg_context: &AggContext,
     ) -> std::result::Result<bool, String> {
-        let result = PIPELINE_MUTEX.try_lock();
-        match result {
-            Ok(_) => match AggProver::new().prove(agg_context) {
-                Ok(()) => Ok(true),
-                Err(e) => {
-                    log::error!("prove_aggregate error {:#?}", e);
-                    Err(e.to_string())
-                }
-            },
-            Err(_) => {
-                log::error!("prove_aggregate busy");
-                Ok(false)
+        let prover = match &self.cancelled {
+            Some(cancelled) => AggProver::with_cancel_flag(cancelled.clone()),
+            None => AggProver::new(),
+        };
+        match prover.prove(agg_context) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                log::error!("prove_aggregate error {:#?}", e);
+                Err(e.to_string())
             }
         }
     }
@@ -63,25 +63,30 @@ impl Pipeline {
         &mut self,
         final_context: &AggAllContext,
     ) -> std::result::Result<bool, String> {
-        let result = PIPELINE_MUTEX.try_lock();
-        match result {
-            Ok(_) => match AggAllProver::new().prove(final_context) {
-                Ok(()) => Ok(true),
-                Err(e) => {
-                    log::error!("prove_aggregate_all error {:#?}", e);
-                    Err(e.to_string())
-                }
-            },
-            Err(_) => {
-                log::error!("prove_aggregate_all busy");
-                Ok(false)
+        let prover = match &self.cancelled {
+            Some(cancelled) => AggAllProver::with_cancel_flag(cancelled.clone()),
+            None => AggAllProver::new(),
+        };
+        match prover.prove(final_context) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                log::error!("prove_aggregate_all error {:#?}", e);
+                Err(e.to_string())
             }
         }
     }
 
-    /// Return prover status
-    pub fn get_status(&mut self) -> bool {
-        let result = PIPELINE_MUTEX.try_lock();
-        result.is_ok()
+    pub fn prove_wrap(&mut self, wrap_context: &WrapContext) -> std::result::Result<bool, String> {
+        let prover = match &self.cancelled {
+            Some(cancelled) => WrapProver::with_cancel_flag(cancelled.clone()),
+            None => WrapProver::new(),
+        };
+        match prover.prove(wrap_context) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                log::error!("prove_wrap error {:#?}", e);
+                Err(e.to_string())
+            }
+        }
     }
 }
@@ -7,18 +7,24 @@ pub use agg_prover::AggProver;
 mod agg_all_prover;
 pub use agg_all_prover::AggAllProver;
 
-use anyhow::Result;
-use lazy_static::lazy_static;
-use std::collections::HashMap;
+mod wrap_prover;
+pub use wrap_prover::WrapProver;
+
+use anyhow::{bail, Result};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use plonky2::field::goldilocks_field::GoldilocksField;
 use plonky2::plonk::config::PoseidonGoldilocksConfig;
+use plonky2::util::serialization::{DefaultGateSerializer, DefaultGeneratorSerializer};
 use zkm_prover::all_stark::AllStark;
 use zkm_prover::config::StarkConfig;
 use zkm_prover::fixed_recursive_verifier::AllRecursiveCircuits;
 
+use common::file;
 use once_cell::sync::OnceCell;
-use std::sync::Mutex;
+use std::sync::RwLock;
 
 pub const MIN_SEG_SIZE: usize = 1 << 16;
 pub const MAX_SEG_SIZE: usize = 1 << 22;
@@ -27,8 +33,24 @@ pub trait Prover<T> {
     fn prove(&self, ctx: &T) -> Result<()>;
 }
 
-const DEGREE_BITS_RANGE: [[std::ops::Range<usize>; 8]; 1] =
-    [[10..22, 12..23, 10..22, 8..22, 6..21, 6..21, 6..22, 13..26]];
+/// Fail the proof once `cancelled` is set, checked between its stages
+fn check_cancelled(cancelled: &Option<Arc<AtomicBool>>) -> Result<()> {
+    match cancelled {
+        Some(cancelled) if cancelled.load(Ordering::Relaxed) => bail!("cancelled"),
+        _ => Ok(()),
+    }
+}
+
+/// Segment sizes with their own circuits, a segment is proved with the circuits of the
+/// smallest size that holds it
+pub const SEG_SIZES: [usize; 3] = [1 << 18, 1 << 20, MAX_SEG_SIZE];
+
+// one row per SEG_SIZES entry
+const DEGREE_BITS_RANGE: [[std::ops::Range<usize>; 8]; 3] = [
+    [10..18, 12..19, 10..18, 8..18, 6..17, 6..17, 6..18, 13..22],
+    [10..20, 12..21, 10..20, 8..20, 6..19, 6..19, 6..20, 13..24],
+    [10..22, 12..23, 10..22, 8..22, 6..21, 6..21, 6..22, 13..26],
+];
 // const DEGREE_BITS_RANGE: [[std::ops::Range<usize>; 6]; 5] = [
 //     [16..17, 12..13, 10..16, 9..12, 15..17, 17..19],
 //     [16..17, 15..17, 12..19, 9..14, 15..17, 19..20],
@@ -37,22 +59,16 @@ const DEGREE_BITS_RANGE: [[std::ops::Range<usize>; 8]; 1] =
 //     [16..18, 16..20, 16..21, 14..15, 18..21, 21..23],
 // ];
 
-lazy_static! {
-    static ref SEG_SIZE_TO_BITS: HashMap<usize, usize> = {
-        let mut map = HashMap::new();
-        map.insert(MAX_SEG_SIZE, 0);
-        map
-    };
+/// Index into SEG_SIZES of the circuits that prove segments of seg_size
+fn seg_size_index(seg_size: usize) -> usize {
+    match SEG_SIZES.iter().position(|size| seg_size <= *size) {
+        Some(index) => index,
+        None => panic!("Invalid segment size, supported: {:?}", SEG_SIZES),
+    }
 }
 
 fn select_degree_bits(seg_size: usize) -> [std::ops::Range<usize>; 8] {
-    match SEG_SIZE_TO_BITS.get(&seg_size) {
-        Some(s) => DEGREE_BITS_RANGE[*s].clone(),
-        None => panic!(
-            "Invalid segment size, supported: {:?}",
-            SEG_SIZE_TO_BITS.keys()
-        ),
-    }
+    DEGREE_BITS_RANGE[seg_size_index(seg_size)].clone()
 }
 
 pub fn valid_seg_size(seg_size: usize) -> bool {
@@ -66,17 +82,136 @@ type F = GoldilocksField;
 const D: usize = 2;
 type C = PoseidonGoldilocksConfig;
 
-static INSTANCE_ALL_CIRCUITS: OnceCell<Mutex<AllRecursiveCircuits<F, C, D>>> = OnceCell::new();
-
-pub fn instance() -> &'static Mutex<AllRecursiveCircuits<F, C, D>> {
-    INSTANCE_ALL_CIRCUITS.get_or_init(|| {
-        let all_stark = AllStark::<F, D>::default();
-        let config = StarkConfig::standard_fast_config();
-        // Preprocess all circuits.
-        Mutex::new(AllRecursiveCircuits::<F, C, D>::new(
-            &all_stark,
-            &select_degree_bits(MAX_SEG_SIZE),
-            &config,
-        ))
-    })
+type Circuits = OnceCell<RwLock<AllRecursiveCircuits<F, C, D>>>;
+
+// one per SEG_SIZES entry, built on first use. read-locked while proving, so that several
+// jobs can share the circuits
+static INSTANCE_ALL_CIRCUITS: [Circuits; 3] = [OnceCell::new(), OnceCell::new(), OnceCell::new()];
+
+static CIRCUITS_CACHE_DIR: OnceCell<String> = OnceCell::new();
+
+// the segment sizes `preload` builds, unset until it is called
+static PRELOAD_SEG_SIZES: OnceCell<Vec<usize>> = OnceCell::new();
+
+// part of the cache file name, change it along with the StarkConfig below
+const CIRCUITS_CONFIG: &str = "standard_fast";
+
+/// Cache the preprocessed circuits in this directory, they are rebuilt on every start
+/// when unset. Call it before the first `instance()`.
+pub fn set_circuits_cache_dir(dir: &str) {
+    let _ = CIRCUITS_CACHE_DIR.set(dir.to_string());
+}
+
+/// Build the circuits of these segment sizes, blocks until all of them are built. Invalid
+/// sizes are skipped.
+pub fn preload(seg_sizes: &[usize]) {
+    let seg_sizes: Vec<usize> = seg_sizes
+        .iter()
+        .copied()
+        .filter(|seg_size| {
+            let valid = valid_seg_size(*seg_size);
+            if !valid {
+                log::warn!("skip preloading invalid segment size {}", seg_size);
+            }
+            valid
+        })
+        .collect();
+    let _ = PRELOAD_SEG_SIZES.set(seg_sizes.clone());
+    for seg_size in seg_sizes {
+        instance(seg_size);
+    }
+}
+
+/// Whether the circuits of every preloaded segment size are built, false until `preload`
+/// is called. A job for another size builds its circuits in `instance()`.
+pub fn is_ready() -> bool {
+    match PRELOAD_SEG_SIZES.get() {
+        Some(seg_sizes) => seg_sizes.iter().all(|seg_size| {
+            INSTANCE_ALL_CIRCUITS[seg_size_index(*seg_size)]
+                .get()
+                .is_some()
+        }),
+        None => false,
+    }
+}
+
+fn circuits_cache_path(dir: &str, degree_bits: &[std::ops::Range<usize>]) -> String {
+    let ranges = degree_bits
+        .iter()
+        .map(|range| format!("{}-{}", range.start, range.end))
+        .collect::<Vec<_>>()
+        .join("_");
+    format!(
+        "{}/all_circuits_{}_{}.bin",
+        dir.trim_end_matches('/'),
+        CIRCUITS_CONFIG,
+        ranges
+    )
+}
+
+fn load_circuits(path: &str) -> Option<AllRecursiveCircuits<F, C, D>> {
+    let data = file::new(path).read().ok()?;
+    let gate_serializer = DefaultGateSerializer;
+    let generator_serializer = DefaultGeneratorSerializer {
+        _phantom: PhantomData::<C>,
+    };
+    match AllRecursiveCircuits::<F, C, D>::from_bytes(
+        &data,
+        &gate_serializer,
+        &generator_serializer,
+    ) {
+        Ok(circuits) => Some(circuits),
+        Err(e) => {
+            log::warn!("load circuits {} failed {:?}", path, e);
+            None
+        }
+    }
+}
+
+fn save_circuits(path: &str, circuits: &AllRecursiveCircuits<F, C, D>) {
+    let gate_serializer = DefaultGateSerializer;
+    let generator_serializer = DefaultGeneratorSerializer {
+        _phantom: PhantomData::<C>,
+    };
+    let data = match circuits.to_bytes(&gate_serializer, &generator_serializer) {
+        Ok(data) => data,
+        Err(e) => {
+            log::warn!("serialize circuits failed {:?}", e);
+            return;
+        }
+    };
+    if let Some((dir, _)) = path.rsplit_once('/') {
+        let _ = file::new(dir).create_dir_all();
+    }
+    match file::new(path).write(&data) {
+        Ok(_) => log::info!("save circuits {} {} bytes", path, data.len()),
+        Err(e) => log::warn!("save circuits {} failed {:?}", path, e),
+    }
+}
+
+fn build_circuits(seg_size: usize) -> AllRecursiveCircuits<F, C, D> {
+    let degree_bits = select_degree_bits(seg_size);
+    let cache_path = CIRCUITS_CACHE_DIR
+        .get()
+        .map(|dir| circuits_cache_path(dir, &degree_bits));
+    if let Some(path) = &cache_path {
+        if let Some(circuits) = load_circuits(path) {
+            log::info!("load circuits {}", path);
+            return circuits;
+        }
+    }
+    let all_stark = AllStark::<F, D>::default();
+    let config = StarkConfig::standard_fast_config();
+    // Preprocess all circuits.
+    let circuits = AllRecursiveCircuits::<F, C, D>::new(&all_stark, &degree_bits, &config);
+    if let Some(path) = &cache_path {
+        save_circuits(path, &circuits);
+    }
+    circuits
+}
+
+/// Circuits for proofs of segments up to seg_size
+pub fn instance(seg_size: usize) -> &'static RwLock<AllRecursiveCircuits<F, C, D>> {
+    let index = seg_size_index(seg_size);
+    INSTANCE_ALL_CIRCUITS[index].get_or_init(|| RwLock::new(build_circuits(SEG_SIZES[index])))
 }
@@ -1,5 +1,8 @@
-use super::Prover;
+use super::{check_cancelled, Prover};
 use crate::contexts::ProveContext;
+use crate::receipt;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 use std::time::Duration;
 
 use plonky2::field::goldilocks_field::GoldilocksField;
@@ -15,12 +18,21 @@ use zkm_prover::generation::state::{AssumptionReceipts, Receipt};
 use common::file;
 
 #[derive(Default)]
-pub struct RootProver {}
+pub struct RootProver {
+    cancelled: Option<Arc<AtomicBool>>,
+}
 
 impl RootProver {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Stop before the next stage of the proof once `cancelled` is set
+    pub fn with_cancel_flag(cancelled: Arc<AtomicBool>) -> Self {
+        RootProver {
+            cancelled: Some(cancelled),
+        }
+    }
 }
 
 impl Prover<ProveContext> for RootProver {
@@ -32,7 +44,7 @@ impl Prover<ProveContext> for RootProver {
         let basedir = ctx.basedir.clone();
         let block_no = ctx.block_no.to_string();
         let seg_path = ctx.seg_path.clone();
-        let _seg_size = ctx.seg_size as usize;
+        let seg_size = ctx.seg_size as usize;
         let receipt_path = ctx.receipt_path.clone();
         let file = String::from("");
         let _args = "".to_string();
@@ -57,15 +69,16 @@ impl Prover<ProveContext> for RootProver {
         let config = StarkConfig::standard_fast_config();
         timing.filter(Duration::from_millis(100)).print();
         timing = TimingTree::new("root_prove init all_circuits", log::Level::Info);
-        let all_circuits = &*crate::provers::instance().lock().unwrap();
+        let all_circuits = &*crate::provers::instance(seg_size).read().unwrap();
         timing.filter(Duration::from_millis(100)).print();
 
         timing = TimingTree::new("root_prove load input", log::Level::Info);
 
-        let seg_data = file::new(&seg_path).read()?;
+        let seg_data = file::read_checked(&seg_path)?;
         let seg_reader = BufReader::new(seg_data.as_slice());
         let input = segment_kernel(&basedir, &block_no, &file, seg_reader);
         timing.filter(Duration::from_millis(100)).print();
+        check_cancelled(&self.cancelled)?;
 
         timing = TimingTree::new("root_prove root", log::Level::Info);
         let receipt = all_circuits.prove_root_with_assumption(
@@ -77,12 +90,13 @@ impl Prover<ProveContext> for RootProver {
         )?;
         all_circuits.verify_root(receipt.clone())?;
         timing.filter(Duration::from_millis(100)).print();
+        check_cancelled(&self.cancelled)?;
 
         timing = TimingTree::new("root_prove write result", log::Level::Info);
 
         // write receipt write file
-        let json_string = serde_json::to_string(&receipt)?;
-        let _ = file::new(&receipt_path).write(json_string.as_bytes())?;
+        let receipt_data = receipt::encode(&receipt)?;
+        file::write_checked(&receipt_path, &receipt_data)?;
         timing.filter(Duration::from_millis(100)).print();
 
         Ok(())
@@ -0,0 +1,147 @@
+use super::{check_cancelled, Prover};
+use crate::contexts::WrapContext;
+use crate::receipt;
+
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use plonky2::field::goldilocks_field::GoldilocksField;
+use plonky2::plonk::circuit_data::CircuitData;
+use plonky2::plonk::config::PoseidonGoldilocksConfig;
+use plonky2::util::serialization::{DefaultGateSerializer, DefaultGeneratorSerializer};
+use plonky2::util::timing::TimingTree;
+
+use plonky2x::backend::circuit::Groth16WrapperParameters;
+use plonky2x::backend::wrapper::wrap::WrappedCircuit;
+use plonky2x::frontend::builder::CircuitBuilder as WrapperBuilder;
+use plonky2x::prelude::DefaultParameters;
+
+use zkm_prover::fixed_recursive_verifier::AllRecursiveCircuits;
+use zkm_prover::generation::state::Receipt;
+
+use common::file;
+
+type F = GoldilocksField;
+const D: usize = 2;
+type C = PoseidonGoldilocksConfig;
+
+#[derive(Default)]
+pub struct WrapProver {
+    cancelled: Option<Arc<AtomicBool>>,
+}
+
+impl WrapProver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop before the next stage of the wrap once `cancelled` is set
+    pub fn with_cancel_flag(cancelled: Arc<AtomicBool>) -> Self {
+        WrapProver {
+            cancelled: Some(cancelled),
+        }
+    }
+}
+
+impl Prover<WrapContext> for WrapProver {
+    fn prove(&self, ctx: &WrapContext) -> anyhow::Result<()> {
+        let mut timing = TimingTree::new("wrap init all_circuits", log::Level::Info);
+        let all_circuits = &*crate::provers::instance(ctx.seg_size as usize)
+            .read()
+            .unwrap();
+        timing.filter(Duration::from_millis(100)).print();
+
+        let agg_receipt_content = file::read_checked(&ctx.agg_receipt_path)?;
+        let agg_receipt: Receipt<F, C, D> = receipt::decode(&agg_receipt_content)?;
+
+        check_cancelled(&self.cancelled)?;
+
+        wrap(all_circuits, &agg_receipt, &ctx.output_dir, &self.cancelled)
+    }
+}
+
+/// Written by plonky2x when the wrapped proof is saved
+const WRAPPED_PROOF_FILES: [&str; 3] = [
+    "proof_with_public_inputs.json",
+    "common_circuit_data.json",
+    "verifier_only_circuit_data.json",
+];
+
+/// Prove the block of the aggregate receipt and wrap it for Groth16. Writes the wrapped
+/// proof with its circuit data, `public_values.json` and `block_public_inputs.json` into
+/// output_dir, each with its checksum. Stops between the stages once `cancelled` is set.
+pub fn wrap(
+    all_circuits: &AllRecursiveCircuits<F, C, D>,
+    agg_receipt: &Receipt<F, C, D>,
+    output_dir: &str,
+    cancelled: &Option<Arc<AtomicBool>>,
+) -> anyhow::Result<()> {
+    type InnerParameters = DefaultParameters;
+    type OuterParameters = Groth16WrapperParameters;
+
+    let mut timing = TimingTree::new("wrap prove_block", log::Level::Info);
+    let block_receipt = all_circuits.prove_block(None, agg_receipt)?;
+    all_circuits.verify_block(&block_receipt)?;
+    timing.filter(Duration::from_millis(100)).print();
+    check_cancelled(cancelled)?;
+
+    timing = TimingTree::new("wrap circuit_data", log::Level::Info);
+    let gate_serializer = DefaultGateSerializer;
+    let generator_serializer = DefaultGeneratorSerializer {
+        _phantom: PhantomData::<C>,
+    };
+    let circuit_data = all_circuits
+        .block
+        .circuit
+        .to_bytes(&gate_serializer, &generator_serializer)
+        .unwrap();
+    let circuit_data = CircuitData::<F, C, D>::from_bytes(
+        circuit_data.as_slice(),
+        &gate_serializer,
+        &generator_serializer,
+    )
+    .unwrap();
+
+    let builder = WrapperBuilder::<DefaultParameters, 2>::new();
+    let mut circuit = builder.build();
+    circuit.set_data(circuit_data);
+    let mut bit_size = vec![32usize; 16];
+    bit_size.extend(vec![8; 32]);
+    bit_size.extend(vec![64; 68]);
+    let wrapped_circuit = WrappedCircuit::<InnerParameters, OuterParameters, D>::build(
+        circuit,
+        Some((vec![], bit_size)),
+    );
+    check_cancelled(cancelled)?;
+    let wrapped_proof = wrapped_circuit.prove(&block_receipt.proof()).unwrap();
+    wrapped_proof.save(output_dir.to_string()).unwrap();
+    let outdir_path = std::path::Path::new(output_dir);
+    for name in WRAPPED_PROOF_FILES {
+        file::record_checksum(outdir_path.join(name).as_os_str().to_str().unwrap())?;
+    }
+
+    let src_public_inputs = match &block_receipt {
+        Receipt::Segments(receipt) => &receipt.proof.public_inputs,
+        Receipt::Composite(recepit) => &recepit.program_receipt.proof.public_inputs,
+    };
+
+    let public_values_file = outdir_path.join("public_values.json");
+    file::write_checked(
+        public_values_file.as_os_str().to_str().unwrap(),
+        &serde_json::to_vec(&block_receipt.values())?,
+    )?;
+
+    let block_public_inputs = serde_json::json!({
+        "public_inputs": src_public_inputs,
+    });
+    let block_public_inputs_file = outdir_path.join("block_public_inputs.json");
+    file::write_checked(
+        block_public_inputs_file.as_os_str().to_str().unwrap(),
+        &serde_json::to_vec(&block_public_inputs)?,
+    )?;
+
+    timing.filter(Duration::from_millis(100)).print();
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use anyhow::{bail, Result};
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+// binary receipts start with the magic, the version and the encoding, JSON receipts with '{'
+const MAGIC: &[u8; 4] = b"ZKMR";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+const ENCODING_BINCODE: u8 = 0;
+const ENCODING_BINCODE_ZSTD: u8 = 1;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// How receipts are written. Readers detect the format, so nodes writing different formats
+/// can share a proof. JSON by default, nodes of older versions only read JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptFormat {
+    #[default]
+    Json,
+    Bincode,
+    BincodeZstd,
+}
+
+static RECEIPT_FORMAT: OnceCell<ReceiptFormat> = OnceCell::new();
+
+/// Write receipts in this format, JSON when unset. Call it before the first receipt
+/// is written.
+pub fn set_receipt_format(format: ReceiptFormat) {
+    let _ = RECEIPT_FORMAT.set(format);
+}
+
+pub fn receipt_format() -> ReceiptFormat {
+    RECEIPT_FORMAT.get().copied().unwrap_or_default()
+}
+
+pub fn encode<T: Serialize>(receipt: &T) -> Result<Vec<u8>> {
+    encode_as(receipt, receipt_format())
+}
+
+pub fn encode_as<T: Serialize>(receipt: &T, format: ReceiptFormat) -> Result<Vec<u8>> {
+    let encoding = match format {
+        ReceiptFormat::Json => return Ok(serde_json::to_vec(receipt)?),
+        ReceiptFormat::Bincode => ENCODING_BINCODE,
+        ReceiptFormat::BincodeZstd => ENCODING_BINCODE_ZSTD,
+    };
+    let payload = bincode::serialize(receipt)?;
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    data.push(encoding);
+    if encoding == ENCODING_BINCODE_ZSTD {
+        zstd::stream::copy_encode(payload.as_slice(), &mut data, ZSTD_LEVEL)?;
+    } else {
+        data.extend_from_slice(&payload);
+    }
+    Ok(data)
+}
+
+/// Read a receipt in any of the formats, receipts without the header are JSON
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    if !data.starts_with(MAGIC) {
+        if data.iter().find(|byte| !byte.is_ascii_whitespace()) != Some(&b'{') {
+            bail!(
+                "not a receipt, header {:?} is neither {:?} nor JSON",
+                &data[..data.len().min(MAGIC.len())],
+                MAGIC
+            );
+        }
+        return Ok(serde_json::from_slice(data)?);
+    }
+    if data.len() < HEADER_LEN {
+        bail!("receipt header truncated");
+    }
+    let version = data[MAGIC.len()];
+    if version != VERSION {
+        bail!("unsupported receipt version {}", version);
+    }
+    let payload = &data[HEADER_LEN..];
+    match data[MAGIC.len() + 1] {
+        ENCODING_BINCODE => Ok(bincode::deserialize(payload)?),
+        ENCODING_BINCODE_ZSTD => Ok(bincode::deserialize(&zstd::stream::decode_all(payload)?)?),
+        encoding => bail!("unsupported receipt encoding {}", encoding),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Receipt {
+        proof: Vec<u8>,
+        public_values: Vec<u64>,
+        circuit: String,
+    }
+
+    fn receipt() -> Receipt {
+        Receipt {
+            proof: (0..=255).cycle().take(4096).collect(),
+            public_values: vec![1, 2, u64::MAX],
+            circuit: "root_65536".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for format in [
+            ReceiptFormat::Json,
+            ReceiptFormat::Bincode,
+            ReceiptFormat::BincodeZstd,
+        ] {
+            let data = encode_as(&receipt(), format).unwrap();
+            assert_eq!(data.starts_with(MAGIC), format != ReceiptFormat::Json);
+            assert_eq!(decode::<Receipt>(&data).unwrap(), receipt());
+        }
+        let bincode = encode_as(&receipt(), ReceiptFormat::Bincode).unwrap();
+        let zstd = encode_as(&receipt(), ReceiptFormat::BincodeZstd).unwrap();
+        assert!(zstd.len() < bincode.len());
+    }
+
+    #[test]
+    fn test_legacy_json() {
+        // written by nodes that only knew JSON receipts
+        let data = serde_json::to_vec_pretty(&receipt()).unwrap();
+        assert_eq!(decode::<Receipt>(&data).unwrap(), receipt());
+    }
+
+    #[test]
+    fn test_bad_header() {
+        let mut data = encode_as(&receipt(), ReceiptFormat::Bincode).unwrap();
+        data[MAGIC.len()] = VERSION + 1;
+        let err = decode::<Receipt>(&data).unwrap_err().to_string();
+        assert_eq!(err, format!("unsupported receipt version {}", VERSION + 1));
+
+        data[MAGIC.len()] = VERSION;
+        data[MAGIC.len() + 1] = 7;
+        let err = decode::<Receipt>(&data).unwrap_err().to_string();
+        assert_eq!(err, "unsupported receipt encoding 7");
+
+        let err = decode::<Receipt>(&data[..MAGIC.len() + 1])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "receipt header truncated");
+
+        data[..MAGIC.len()].copy_from_slice(b"ZKMX");
+        let err = decode::<Receipt>(&data).unwrap_err().to_string();
+        assert!(err.starts_with("not a receipt"), "{}", err);
+    }
+}
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE stage_task ADD COLUMN `prove_total` int not null default 0 AFTER `step`;
+ALTER TABLE stage_task ADD COLUMN `agg_total` int not null default 0 AFTER `prove_total`;
\ No newline at end of file
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE stage_task ADD COLUMN `error_message` text AFTER `result`;
\ No newline at end of file
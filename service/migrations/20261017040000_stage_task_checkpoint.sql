@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE stage_task ADD COLUMN `checkpoint` longtext AFTER `context`;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE stage_task ADD COLUMN `priority` int not null default 0 AFTER `agg_total`;
+ALTER TABLE stage_task ADD COLUMN `deadline_ts` bigint not null default 0 AFTER `priority`;
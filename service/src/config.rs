@@ -1,7 +1,11 @@
+use crate::prover_node::{MemoryClass, NodeCapabilities};
 use common::file;
 use log::error;
 use once_cell::sync::OnceCell;
+use prover::receipt::ReceiptFormat;
 use serde_derive::Deserialize;
+use stage::stage::{AggStrategy, RetryPolicies};
+use std::collections::HashMap;
 use std::sync::Mutex;
 
 static INSTANCE: OnceCell<Mutex<RuntimeConfig>> = OnceCell::new();
@@ -10,6 +14,37 @@ pub fn instance() -> &'static Mutex<RuntimeConfig> {
     INSTANCE.get_or_init(|| Mutex::new(RuntimeConfig::new()))
 }
 
+/// How many jobs of each type a prover node runs at once
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct JobLimits {
+    pub split: u32,
+    pub prove: u32,
+    pub agg: u32,
+    pub agg_all: u32,
+    pub wrap: u32,
+}
+
+impl Default for JobLimits {
+    fn default() -> Self {
+        JobLimits {
+            split: 1,
+            prove: 1,
+            agg: 1,
+            agg_all: 1,
+            wrap: 1,
+        }
+    }
+}
+
+/// A prover node of the stage with the tasks it takes
+#[derive(Debug, Deserialize, Clone)]
+pub struct NodeConfig {
+    pub addr: String,
+    #[serde(flatten)]
+    pub capabilities: NodeCapabilities,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct RuntimeConfig {
     pub addr: String,
@@ -23,6 +58,21 @@ pub struct RuntimeConfig {
     pub ca_cert_path: Option<String>,
     pub cert_path: Option<String>,
     pub key_path: Option<String>,
+    pub retry_policy: Option<RetryPolicies>,
+    pub agg_strategy: Option<AggStrategy>,
+    pub user_weights: Option<HashMap<String, u32>>,
+    pub job_limits: Option<JobLimits>,
+    pub circuits_cache_dir: Option<String>,
+    pub preload_seg_sizes: Option<Vec<u32>>,
+    pub receipt_format: Option<ReceiptFormat>,
+    pub prover_name: Option<String>,
+    pub node_token: Option<String>,
+    pub stage_addr: Option<String>,
+    pub advertise_addr: Option<String>,
+    pub prover_nodes: Option<Vec<NodeConfig>>,
+    pub capabilities: Option<NodeCapabilities>,
+    // role => smallest memory class of the nodes it runs on
+    pub min_memory_class: Option<HashMap<String, MemoryClass>>,
 }
 
 impl RuntimeConfig {
@@ -39,6 +89,20 @@ impl RuntimeConfig {
             ca_cert_path: None,
             cert_path: None,
             key_path: None,
+            retry_policy: None,
+            agg_strategy: None,
+            user_weights: None,
+            job_limits: None,
+            circuits_cache_dir: None,
+            preload_seg_sizes: None,
+            receipt_format: None,
+            prover_name: None,
+            node_token: None,
+            stage_addr: None,
+            advertise_addr: None,
+            prover_nodes: None,
+            capabilities: None,
+            min_memory_class: None,
         }
     }
 
@@ -120,6 +184,76 @@ impl RuntimeConfig {
             .unwrap()
             .key_path
             .clone_from(&config.key_path);
+        instance()
+            .lock()
+            .unwrap()
+            .retry_policy
+            .clone_from(&config.retry_policy);
+        instance()
+            .lock()
+            .unwrap()
+            .agg_strategy
+            .clone_from(&config.agg_strategy);
+        instance()
+            .lock()
+            .unwrap()
+            .user_weights
+            .clone_from(&config.user_weights);
+        instance()
+            .lock()
+            .unwrap()
+            .job_limits
+            .clone_from(&config.job_limits);
+        instance()
+            .lock()
+            .unwrap()
+            .circuits_cache_dir
+            .clone_from(&config.circuits_cache_dir);
+        instance()
+            .lock()
+            .unwrap()
+            .preload_seg_sizes
+            .clone_from(&config.preload_seg_sizes);
+        instance()
+            .lock()
+            .unwrap()
+            .receipt_format
+            .clone_from(&config.receipt_format);
+        instance()
+            .lock()
+            .unwrap()
+            .prover_name
+            .clone_from(&config.prover_name);
+        instance()
+            .lock()
+            .unwrap()
+            .node_token
+            .clone_from(&config.node_token);
+        instance()
+            .lock()
+            .unwrap()
+            .stage_addr
+            .clone_from(&config.stage_addr);
+        instance()
+            .lock()
+            .unwrap()
+            .advertise_addr
+            .clone_from(&config.advertise_addr);
+        instance()
+            .lock()
+            .unwrap()
+            .prover_nodes
+            .clone_from(&config.prover_nodes);
+        instance()
+            .lock()
+            .unwrap()
+            .capabilities
+            .clone_from(&config.capabilities);
+        instance()
+            .lock()
+            .unwrap()
+            .min_memory_class
+            .clone_from(&config.min_memory_class);
         Some(config)
     }
 }
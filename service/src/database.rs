@@ -5,8 +5,13 @@ pub struct StageTask {
     pub status: i32,
     pub context: Option<String>,
     pub result: Option<String>,
+    pub error_message: Option<String>,
     pub check_at: i64,
     pub step: i32,
+    pub prove_total: i32,
+    pub agg_total: i32,
+    pub priority: i32,
+    pub deadline_ts: i64,
 }
 
 #[warn(unused_macros)]
@@ -39,6 +44,7 @@ impl Database {
         Database { db_pool }
     }
 
+    /// Higher priority first, then the earliest deadline, proofs without a deadline last
     #[allow(dead_code)]
     pub async fn get_incomplete_stage_tasks(
         &self,
@@ -48,7 +54,7 @@ impl Database {
     ) -> anyhow::Result<Vec<StageTask>> {
         let rows = sqlx::query_as!(
             StageTask,
-            "SELECT id, status, context, result, check_at, step from stage_task where status = ? and check_at < ? limit ?",
+            "SELECT id, status, context, result, error_message, check_at, step, prove_total, agg_total, priority, deadline_ts from stage_task where status = ? and check_at < ? order by priority desc, deadline_ts = 0, deadline_ts, created_at limit ?",
             status,
             check_at,
             limit,
@@ -62,7 +68,7 @@ impl Database {
     pub async fn get_stage_task(&self, proof_id: &str) -> anyhow::Result<StageTask> {
         let row = sqlx::query_as!(
             StageTask,
-            "SELECT id, status, context, result, check_at, step from stage_task where id = ?",
+            "SELECT id, status, context, result, error_message, check_at, step, prove_total, agg_total, priority, deadline_ts from stage_task where id = ?",
             proof_id,
         )
         .fetch_one(&self.db_pool)
@@ -77,13 +83,17 @@ impl Database {
         address: &str,
         status: i32,
         context: &str,
+        priority: i32,
+        deadline_ts: i64,
     ) -> anyhow::Result<bool> {
         sqlx::query!(
-            "INSERT INTO stage_task (id, address, status, context) values (?,?,?,?)",
+            "INSERT INTO stage_task (id, address, status, context, priority, deadline_ts) values (?,?,?,?,?,?)",
             proof_id,
             address,
             status,
-            context
+            context,
+            priority,
+            deadline_ts
         )
         .execute(&self.db_pool)
         .await?;
@@ -108,6 +118,101 @@ impl Database {
         Ok(true)
     }
 
+    #[allow(dead_code)]
+    /// Fail a proof unless it was cancelled meanwhile, the cancellation is kept
+    pub async fn update_stage_task_error(
+        &self,
+        proof_id: &str,
+        status: i32,
+        error_message: &str,
+        cancelled_status: i32,
+    ) -> anyhow::Result<bool> {
+        let rows_affected = sqlx::query!(
+            "UPDATE stage_task set status = ?, error_message = ? where id = ? and status != ?",
+            status,
+            error_message,
+            proof_id,
+            cancelled_status
+        )
+        .execute(&self.db_pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected == 1)
+    }
+
+    #[allow(dead_code)]
+    pub async fn cancel_stage_task(
+        &self,
+        proof_id: &str,
+        address: &str,
+        status: i32,
+        old_status: i32,
+    ) -> anyhow::Result<u64> {
+        let rows_affected = sqlx::query!(
+            "UPDATE stage_task set status = ? where id = ? and address = ? and status = ?",
+            status,
+            proof_id,
+            address,
+            old_status
+        )
+        .execute(&self.db_pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+
+    #[allow(dead_code)]
+    pub async fn retry_stage_task(
+        &self,
+        proof_id: &str,
+        address: &str,
+        status: i32,
+        failed_status: &[i32; 5],
+    ) -> anyhow::Result<u64> {
+        let rows_affected = sqlx::query!(
+            "UPDATE stage_task set status = ?, error_message = NULL, check_at = 0 where id = ? and address = ? and status in (?, ?, ?, ?, ?)",
+            status,
+            proof_id,
+            address,
+            failed_status[0],
+            failed_status[1],
+            failed_status[2],
+            failed_status[3],
+            failed_status[4]
+        )
+        .execute(&self.db_pool)
+        .await?
+        .rows_affected();
+        Ok(rows_affected)
+    }
+
+    #[allow(dead_code)]
+    pub async fn get_stage_task_checkpoint(
+        &self,
+        proof_id: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let row = sqlx::query!("SELECT checkpoint from stage_task where id = ?", proof_id)
+            .fetch_one(&self.db_pool)
+            .await?;
+        Ok(row.checkpoint)
+    }
+
+    #[allow(dead_code)]
+    pub async fn update_stage_task_checkpoint(
+        &self,
+        proof_id: &str,
+        checkpoint: &str,
+    ) -> anyhow::Result<bool> {
+        sqlx::query!(
+            "UPDATE stage_task set checkpoint = ? where id = ?",
+            checkpoint,
+            proof_id
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
     #[allow(dead_code)]
     pub async fn update_stage_task_check_at(
         &self,
@@ -129,10 +234,28 @@ impl Database {
         Ok(rows_affected)
     }
 
+    #[allow(dead_code)]
+    pub async fn update_stage_task_progress(
+        &self,
+        proof_id: &str,
+        prove_total: i32,
+        agg_total: i32,
+    ) -> anyhow::Result<bool> {
+        sqlx::query!(
+            "UPDATE stage_task set prove_total = ?, agg_total = ? where id = ?",
+            prove_total,
+            agg_total,
+            proof_id
+        )
+        .execute(&self.db_pool)
+        .await?;
+        Ok(true)
+    }
+
     #[allow(dead_code)]
     pub async fn insert_prove_task(&self, task: &ProveTask) -> anyhow::Result<bool> {
         sqlx::query!(
-            "INSERT INTO prove_task (id, itype, proof_id, status, time_cost, node_info, content, check_at) values (?,?,?,?,?,?,?,?)",
+            "INSERT INTO prove_task (id, itype, proof_id, status, time_cost, node_info, content, check_at) values (?,?,?,?,?,?,?,?) ON DUPLICATE KEY UPDATE status = VALUES(status), time_cost = VALUES(time_cost), node_info = VALUES(node_info), content = VALUES(content)",
             task.id,
             task.itype,
             task.proof_id,
@@ -0,0 +1,85 @@
+use crate::config;
+use crate::prover_node;
+use crate::prover_node::MemoryClass;
+use crate::stage_service::stage_service::stage_service_client::StageServiceClient;
+use crate::stage_service::stage_service::{HeartbeatRequest, RegisterNodeRequest, Status};
+use common::tls::Config as TlsConfig;
+use tokio::time;
+use tonic::Request;
+
+/// Announce this prover node to the stage at `stage_addr` and keep it registered with
+/// heartbeats. The node registers again when the stage dropped it.
+pub async fn start(tls_config: Option<TlsConfig>) {
+    let (stage_addr, addr, token, capabilities) = {
+        let config = config::instance().lock().unwrap();
+        (
+            config.stage_addr.clone(),
+            config.advertise_addr.clone().unwrap_or(config.addr.clone()),
+            config.node_token.clone().unwrap_or_default(),
+            config.capabilities.clone().unwrap_or_default(),
+        )
+    };
+    let stage_addr = match stage_addr {
+        Some(stage_addr) => stage_addr,
+        None => return,
+    };
+    let channel = prover_node::endpoint(&stage_addr, tls_config).connect_lazy();
+    let mut client = StageServiceClient::new(channel);
+    tokio::spawn(async move {
+        let mut registered = false;
+        let mut interval = prover_node::HEARTBEAT_INTERVAL_SECS;
+        loop {
+            if registered {
+                let request = HeartbeatRequest {
+                    addr: addr.clone(),
+                    token: token.clone(),
+                };
+                match client.heartbeat(Request::new(request)).await {
+                    Ok(response) => {
+                        if response.get_ref().status != Status::Success as u32 {
+                            log::warn!(
+                                "[heartbeat] {} {}",
+                                stage_addr,
+                                response.get_ref().error_message
+                            );
+                            registered = false;
+                            continue;
+                        }
+                    }
+                    Err(e) => log::warn!("[heartbeat] {} {:?}", stage_addr, e),
+                }
+            } else {
+                let request = RegisterNodeRequest {
+                    addr: addr.clone(),
+                    snark: false,
+                    token: token.clone(),
+                    roles: capabilities.roles.clone(),
+                    max_seg_size: capabilities.max_seg_size,
+                    memory_class: match capabilities.memory_class {
+                        MemoryClass::Small => "small",
+                        MemoryClass::Medium => "medium",
+                        MemoryClass::Large => "large",
+                    }
+                    .to_string(),
+                };
+                match client.register_node(Request::new(request)).await {
+                    Ok(response) => {
+                        if response.get_ref().status == Status::Success as u32 {
+                            log::info!("[register_node] {} as {}", stage_addr, addr);
+                            registered = true;
+                            interval = response.get_ref().heartbeat_interval_secs.max(1);
+                        } else {
+                            log::warn!(
+                                "[register_node] {} {}",
+                                stage_addr,
+                                response.get_ref().error_message
+                            );
+                        }
+                    }
+                    Err(e) => log::warn!("[register_node] {} {:?}", stage_addr, e),
+                }
+            }
+            time::sleep(time::Duration::from_secs(interval)).await;
+        }
+    });
+}
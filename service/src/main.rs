@@ -13,10 +13,13 @@ use prometheus::{Encoder, TextEncoder};
 
 mod config;
 mod database;
+mod heartbeat;
 mod metrics;
 mod prover_client;
 mod prover_node;
 mod prover_service;
+mod scheduler;
+mod stage_events;
 mod stage_service;
 mod stage_worker;
 
@@ -41,21 +44,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         for node in &runtime_config.prover_addrs {
             nodes_data.add_node(ProverNode::new(node));
         }
+        for node in runtime_config.prover_nodes.iter().flatten() {
+            nodes_data.add_node(ProverNode::with_capabilities(
+                &node.addr,
+                node.capabilities.clone(),
+            ));
+        }
         for node in &runtime_config.snark_addrs {
             nodes_data.add_snark_node(ProverNode::new(node));
         }
     }
     let mut server = Server::builder();
-    if runtime_config.key_path.is_some() {
-        let tls_config = TlsConfig::new(
-            runtime_config
-                .ca_cert_path
-                .clone()
-                .unwrap_or("".to_string()),
-            runtime_config.cert_path.clone().unwrap(),
-            runtime_config.key_path.clone().unwrap(),
+    let tls_config = if runtime_config.key_path.is_some() {
+        Some(
+            TlsConfig::new(
+                runtime_config
+                    .ca_cert_path
+                    .clone()
+                    .unwrap_or("".to_string()),
+                runtime_config.cert_path.clone().unwrap(),
+                runtime_config.key_path.clone().unwrap(),
+            )
+            .await?,
         )
-        .await?;
+    } else {
+        None
+    };
+    if let Some(tls_config) = tls_config.clone() {
         let mut server_tls_config = ServerTlsConfig::new();
         if let Some(ca_cert) = tls_config.ca_cert {
             server_tls_config = server_tls_config.client_ca_root(ca_cert);
@@ -71,6 +86,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .add_service(StageServiceServer::new(stage))
             .serve(addr)
     } else {
+        if let Some(dir) = &runtime_config.circuits_cache_dir {
+            prover::provers::set_circuits_cache_dir(dir);
+        }
+        if let Some(format) = runtime_config.receipt_format {
+            prover::receipt::set_receipt_format(format);
+        }
+        // preprocess the circuits in the background, GetStatus reports booting until done
+        let seg_sizes = runtime_config
+            .preload_seg_sizes
+            .clone()
+            .unwrap_or(vec![prover::provers::MAX_SEG_SIZE as u32]);
+        tokio::task::spawn_blocking(move || {
+            let seg_sizes: Vec<usize> = seg_sizes.iter().map(|size| *size as usize).collect();
+            prover::provers::preload(&seg_sizes);
+        });
+        heartbeat::start(tls_config).await;
+        // drain on SIGTERM or ctrl-c, the node exits once the running jobs are done
+        tokio::spawn(async {
+            let mut terminate =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                    .expect("SIGTERM handler");
+            tokio::select! {
+                _ = terminate.recv() => {},
+                _ = tokio::signal::ctrl_c() => {},
+            }
+            prover_service::drain();
+        });
         let prover = prover_service::ProverServiceSVC::default();
         server
             .add_service(ProverServiceServer::new(prover))
@@ -102,6 +144,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tokio::select! {
         res = grpc_server => res?,
         res = metrics_server => res?,
+        _ = prover_service::drained(), if !args.stage => {},
     }
     Ok(())
 }
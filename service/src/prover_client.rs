@@ -3,48 +3,92 @@ use common::tls::Config as TlsConfig;
 use prover_service::prover_service_client::ProverServiceClient;
 use prover_service::AggregateAllRequest;
 use prover_service::AggregateRequest;
+use prover_service::CancelTaskRequest;
 use prover_service::FinalProofRequest;
 use prover_service::GetTaskResultRequest;
 use prover_service::ProveRequest;
 use prover_service::SplitElfRequest;
+use prover_service::WrapRequest;
 
+use stage::stage::original_task_id;
 use stage::tasks::{
-    AggAllTask, AggTask, FinalTask, ProveTask, SplitTask, TASK_STATE_FAILED, TASK_STATE_PROCESSING,
-    TASK_STATE_SUCCESS, TASK_STATE_UNPROCESSED, TASK_TIMEOUT,
+    AggAllTask, AggTask, FinalTask, ProveTask, RunningTask, SplitTask, Task, WrapTask,
+    TASK_ITYPE_AGG, TASK_ITYPE_AGGALL, TASK_ITYPE_FINAL, TASK_ITYPE_PROVE, TASK_ITYPE_SPLIT,
+    TASK_ITYPE_WRAP, TASK_STATE_FAILED, TASK_STATE_PROCESSING, TASK_STATE_SUCCESS,
+    TASK_STATE_UNPROCESSED, TASK_TIMEOUT,
 };
+
 use tonic::Request;
 
 use self::prover_service::ResultCode;
 use crate::prover_client::prover_service::AggregateInput;
-use crate::prover_node::ProverNode;
+use crate::prover_node::{min_memory_class, ProverNode};
+use crate::scheduler;
+use lazy_static::lazy_static;
 use prover_service::GetTaskResultResponse;
+use prover_service::Result as TaskResult;
+use prover_service::TaskState;
+use std::collections::HashMap;
+use std::sync::Mutex;
 use std::time::Duration;
+use tokio::sync::mpsc::Sender;
 use tonic::transport::Channel;
 
 pub mod prover_service {
     tonic::include_proto!("prover.v1");
 }
 
+// seconds between two GetTaskResult calls while a job runs on a prover node
+const JOB_POLL_SECS: u64 = 2;
+// seconds for the prover node to accept a job
+const JOB_SUBMIT_TIMEOUT: u64 = 60;
+
+lazy_static! {
+    // (computed_request_id, node addr) => proof_id, a speculative copy runs under the id of
+    // its task with `SPECULATIVE_SUFFIX`
+    static ref RUNNING_TASKS: Mutex<HashMap<(String, String), String>> =
+        Mutex::new(HashMap::new());
+}
+
+fn track_task(proof_id: &str, task_id: &str, addrs: &str) {
+    RUNNING_TASKS.lock().unwrap().insert(
+        (task_id.to_string(), addrs.to_string()),
+        proof_id.to_string(),
+    );
+}
+
+fn untrack_task(task_id: &str, addrs: &str) {
+    RUNNING_TASKS
+        .lock()
+        .unwrap()
+        .remove(&(task_id.to_string(), addrs.to_string()));
+}
+
+/// Tell the stage which node took the task, before its result
+async fn notify_running(
+    running: &Sender<Task>,
+    itype: i32,
+    task_id: &str,
+    addrs: &str,
+    start_ts: u64,
+    speculative: bool,
+) {
+    let task = RunningTask {
+        itype,
+        task_id: task_id.to_string(),
+        node_info: addrs.to_string(),
+        start_ts,
+        speculative,
+    };
+    let _ = running.send(Task::Running(task)).await;
+}
+
 pub fn get_nodes() -> Vec<ProverNode> {
     let nodes_lock = crate::prover_node::instance();
     let nodes_data = nodes_lock.lock().unwrap();
     nodes_data.get_nodes()
 }
 
-pub async fn get_idle_client(
-    tls_config: Option<TlsConfig>,
-) -> Option<(String, ProverServiceClient<Channel>)> {
-    let nodes: Vec<ProverNode> = get_nodes();
-    for mut node in nodes {
-        let client = node.is_active(tls_config.clone()).await;
-        if let Some(client) = client {
-            return Some((node.addr.clone(), client));
-        }
-    }
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-    None
-}
-
 pub fn get_snark_nodes() -> Vec<ProverNode> {
     let nodes_lock = crate::prover_node::instance();
     let nodes_data = nodes_lock.lock().unwrap();
@@ -55,7 +99,11 @@ pub async fn get_snark_client(
     tls_config: Option<TlsConfig>,
 ) -> Option<(String, ProverServiceClient<Channel>)> {
     let nodes: Vec<ProverNode> = get_snark_nodes();
-    for mut node in nodes {
+    let min_class = min_memory_class(TASK_ITYPE_FINAL);
+    for mut node in nodes
+        .into_iter()
+        .filter(|node| node.capabilities.can_run(TASK_ITYPE_FINAL, 0, min_class))
+    {
         let client = node.is_active(tls_config.clone()).await;
         if let Some(client) = client {
             return Some((node.addr.clone(), client));
@@ -75,10 +123,72 @@ pub fn result_code_to_state(code: i32) -> u32 {
     }
 }
 
-pub async fn split(mut split_task: SplitTask, tls_config: Option<TlsConfig>) -> Option<SplitTask> {
+/// Poll the prover node until the job it accepted is done or failed. Failed polls are
+/// retried, so a network blip does not lose the result. None when the node lost the job,
+/// was dropped for missed heartbeats or the job did not finish within TASK_TIMEOUT.
+async fn wait_task_result(
+    client: &mut ProverServiceClient<Channel>,
+    addrs: &str,
+    proof_id: &str,
+    task_id: &str,
+    accepted: Option<TaskResult>,
+) -> Option<GetTaskResultResponse> {
+    let accepted = accepted?;
+    match ResultCode::from_i32(accepted.code) {
+        Some(ResultCode::Ok) | Some(ResultCode::Unspecified) => {}
+        // rejected, nothing to wait for
+        _ => {
+            return Some(GetTaskResultResponse {
+                proof_id: proof_id.to_string(),
+                computed_request_id: task_id.to_string(),
+                result: Some(accepted),
+                state: TaskState::Failed.into(),
+                total_steps: 0,
+            });
+        }
+    }
+    let start = std::time::Instant::now();
+    while start.elapsed().as_secs() < TASK_TIMEOUT {
+        if !crate::prover_node::instance()
+            .lock()
+            .unwrap()
+            .contains(addrs)
+        {
+            log::warn!(
+                "[wait_task_result] {}:{} node {} dropped",
+                proof_id,
+                task_id,
+                addrs
+            );
+            return None;
+        }
+        if let Some(response) = get_task_result(client, proof_id, task_id).await {
+            match TaskState::from_i32(response.state) {
+                Some(TaskState::Done) | Some(TaskState::Failed) => return Some(response),
+                Some(TaskState::Unknown) | None => {
+                    log::warn!("[wait_task_result] {}:{} job lost", proof_id, task_id);
+                    return None;
+                }
+                _ => {}
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(JOB_POLL_SECS)).await;
+    }
+    log::warn!("[wait_task_result] {}:{} timeout", proof_id, task_id);
+    None
+}
+
+pub async fn split(mut split_task: SplitTask, _tls_config: Option<TlsConfig>) -> Option<SplitTask> {
     split_task.state = TASK_STATE_UNPROCESSED;
-    let client = get_idle_client(tls_config).await;
-    if let Some((addrs, mut client)) = client {
+    split_task.node_info.clear();
+    let client = scheduler::acquire(
+        &split_task.proof_id,
+        TASK_ITYPE_SPLIT,
+        split_task.seg_size,
+        &split_task.excluded_nodes,
+    )
+    .await;
+    if let Some((addrs, mut client, _lease)) = client {
         let request = SplitElfRequest {
             proof_id: split_task.proof_id.clone(),
             computed_request_id: split_task.task_id.clone(),
@@ -99,19 +209,37 @@ pub async fn split(mut split_task: SplitTask, tls_config: Option<TlsConfig>) ->
             request.computed_request_id
         );
         log::debug!("split request {:#?}", request);
+        track_task(&split_task.proof_id, &split_task.task_id, &addrs);
         let mut grpc_request = Request::new(request);
-        grpc_request.set_timeout(Duration::from_secs(TASK_TIMEOUT));
-        let response = client.split_elf(grpc_request).await;
-        if let Ok(response) = response {
-            if let Some(response_result) = response.get_ref().result.as_ref() {
+        grpc_request.set_timeout(Duration::from_secs(JOB_SUBMIT_TIMEOUT));
+        let response = match client.split_elf(grpc_request).await {
+            Ok(response) => {
+                let accepted = response.into_inner().result;
+                wait_task_result(
+                    &mut client,
+                    &addrs,
+                    &split_task.proof_id,
+                    &split_task.task_id,
+                    accepted,
+                )
+                .await
+            }
+            Err(_) => None,
+        };
+        untrack_task(&split_task.task_id, &addrs);
+        if let Some(response) = response {
+            if let Some(response_result) = response.result.as_ref() {
                 log::debug!("split response {:#?}", response);
                 split_task.state = result_code_to_state(response_result.code);
                 split_task.node_info = addrs;
-                split_task.total_steps = response.get_ref().total_steps;
+                if split_task.state == TASK_STATE_FAILED {
+                    split_task.errmsg.clone_from(&response_result.message);
+                }
+                split_task.total_steps = response.total_steps;
                 log::info!(
                     "[split] rpc {}:{} code:{:?} message:{:?} end",
-                    response.get_ref().proof_id,
-                    response.get_ref().computed_request_id,
+                    response.proof_id,
+                    response.computed_request_id,
                     response_result.code,
                     response_result.message,
                 );
@@ -123,10 +251,31 @@ pub async fn split(mut split_task: SplitTask, tls_config: Option<TlsConfig>) ->
     Some(split_task)
 }
 
-pub async fn prove(mut prove_task: ProveTask, tls_config: Option<TlsConfig>) -> Option<ProveTask> {
+pub async fn prove(
+    mut prove_task: ProveTask,
+    _tls_config: Option<TlsConfig>,
+    running: Sender<Task>,
+) -> Option<ProveTask> {
     prove_task.state = TASK_STATE_UNPROCESSED;
-    let client = get_idle_client(tls_config).await;
-    if let Some((addrs, mut client)) = client {
+    prove_task.node_info.clear();
+    let client = scheduler::acquire(
+        &prove_task.proof_id,
+        TASK_ITYPE_PROVE,
+        prove_task.seg_size,
+        &prove_task.excluded_nodes,
+    )
+    .await;
+    if let Some((addrs, mut client, _lease)) = client {
+        prove_task.start_ts = crate::stage_worker::now_timestamp();
+        notify_running(
+            &running,
+            TASK_ITYPE_PROVE,
+            &prove_task.task_id,
+            &addrs,
+            prove_task.start_ts,
+            prove_task.speculative,
+        )
+        .await;
         let request = ProveRequest {
             proof_id: prove_task.proof_id.clone(),
             computed_request_id: prove_task.task_id.clone(),
@@ -144,18 +293,36 @@ pub async fn prove(mut prove_task: ProveTask, tls_config: Option<TlsConfig>) ->
             request.seg_path,
         );
         log::debug!("prove request {:#?}", request);
+        track_task(&prove_task.proof_id, &prove_task.task_id, &addrs);
         let mut grpc_request = Request::new(request);
-        grpc_request.set_timeout(Duration::from_secs(TASK_TIMEOUT));
-        let response = client.prove(grpc_request).await;
-        if let Ok(response) = response {
-            if let Some(response_result) = response.get_ref().result.as_ref() {
+        grpc_request.set_timeout(Duration::from_secs(JOB_SUBMIT_TIMEOUT));
+        let response = match client.prove(grpc_request).await {
+            Ok(response) => {
+                let accepted = response.into_inner().result;
+                wait_task_result(
+                    &mut client,
+                    &addrs,
+                    &prove_task.proof_id,
+                    &prove_task.task_id,
+                    accepted,
+                )
+                .await
+            }
+            Err(_) => None,
+        };
+        untrack_task(&prove_task.task_id, &addrs);
+        if let Some(response) = response {
+            if let Some(response_result) = response.result.as_ref() {
                 log::debug!("prove response {:#?}", response);
                 prove_task.state = result_code_to_state(response_result.code);
                 prove_task.node_info = addrs;
+                if prove_task.state == TASK_STATE_FAILED {
+                    prove_task.errmsg.clone_from(&response_result.message);
+                }
                 log::info!(
                     "[prove] rpc {}:{} code:{:?} message:{:?} end",
-                    response.get_ref().proof_id,
-                    response.get_ref().computed_request_id,
+                    response.proof_id,
+                    response.computed_request_id,
                     response_result.code,
                     response_result.message,
                 );
@@ -167,10 +334,31 @@ pub async fn prove(mut prove_task: ProveTask, tls_config: Option<TlsConfig>) ->
     Some(prove_task)
 }
 
-pub async fn aggregate(mut agg_task: AggTask, tls_config: Option<TlsConfig>) -> Option<AggTask> {
+pub async fn aggregate(
+    mut agg_task: AggTask,
+    _tls_config: Option<TlsConfig>,
+    running: Sender<Task>,
+) -> Option<AggTask> {
     agg_task.state = TASK_STATE_UNPROCESSED;
-    let client = get_idle_client(tls_config).await;
-    if let Some((addrs, mut client)) = client {
+    agg_task.node_info.clear();
+    let client = scheduler::acquire(
+        &agg_task.proof_id,
+        TASK_ITYPE_AGG,
+        agg_task.seg_size,
+        &agg_task.excluded_nodes,
+    )
+    .await;
+    if let Some((addrs, mut client, _lease)) = client {
+        agg_task.start_ts = crate::stage_worker::now_timestamp();
+        notify_running(
+            &running,
+            TASK_ITYPE_AGG,
+            &agg_task.task_id,
+            &addrs,
+            agg_task.start_ts,
+            agg_task.speculative,
+        )
+        .await;
         let request = AggregateRequest {
             proof_id: agg_task.proof_id.clone(),
             computed_request_id: agg_task.task_id.clone(),
@@ -188,7 +376,8 @@ pub async fn aggregate(mut agg_task: AggTask, tls_config: Option<TlsConfig>) ->
             }),
             agg_receipt_path: agg_task.output_receipt_path.clone(),
             output_dir: agg_task.output_dir.clone(),
-            is_final: agg_task.is_final,
+            // the root receipt is wrapped by the wrap task
+            is_final: false,
         };
         log::info!(
             "[aggregate] rpc {}:{} {}+{} start",
@@ -198,18 +387,36 @@ pub async fn aggregate(mut agg_task: AggTask, tls_config: Option<TlsConfig>) ->
             request.input2.clone().expect("need input2").receipt_path,
         );
         log::debug!("aggregate request {:#?}", request);
+        track_task(&agg_task.proof_id, &agg_task.task_id, &addrs);
         let mut grpc_request = Request::new(request);
-        grpc_request.set_timeout(Duration::from_secs(TASK_TIMEOUT));
-        let response = client.aggregate(grpc_request).await;
-        if let Ok(response) = response {
-            if let Some(response_result) = response.get_ref().result.as_ref() {
+        grpc_request.set_timeout(Duration::from_secs(JOB_SUBMIT_TIMEOUT));
+        let response = match client.aggregate(grpc_request).await {
+            Ok(response) => {
+                let accepted = response.into_inner().result;
+                wait_task_result(
+                    &mut client,
+                    &addrs,
+                    &agg_task.proof_id,
+                    &agg_task.task_id,
+                    accepted,
+                )
+                .await
+            }
+            Err(_) => None,
+        };
+        untrack_task(&agg_task.task_id, &addrs);
+        if let Some(response) = response {
+            if let Some(response_result) = response.result.as_ref() {
                 log::debug!("aggregate response {:#?}", response);
                 agg_task.state = result_code_to_state(response_result.code);
                 agg_task.node_info = addrs;
+                if agg_task.state == TASK_STATE_FAILED {
+                    agg_task.errmsg.clone_from(&response_result.message);
+                }
                 log::info!(
                     "[aggregate] rpc {}:{} code:{:?} message:{:?} end",
-                    response.get_ref().proof_id,
-                    response.get_ref().computed_request_id,
+                    response.proof_id,
+                    response.computed_request_id,
                     response_result.code,
                     response_result.message,
                 );
@@ -223,11 +430,18 @@ pub async fn aggregate(mut agg_task: AggTask, tls_config: Option<TlsConfig>) ->
 
 pub async fn aggregate_all(
     mut agg_all_task: AggAllTask,
-    tls_config: Option<TlsConfig>,
+    _tls_config: Option<TlsConfig>,
 ) -> Option<AggAllTask> {
     agg_all_task.state = TASK_STATE_UNPROCESSED;
-    let client = get_idle_client(tls_config).await;
-    if let Some((addrs, mut client)) = client {
+    agg_all_task.node_info.clear();
+    let client = scheduler::acquire(
+        &agg_all_task.proof_id,
+        TASK_ITYPE_AGGALL,
+        agg_all_task.seg_size,
+        &agg_all_task.excluded_nodes,
+    )
+    .await;
+    if let Some((addrs, mut client, _lease)) = client {
         let request = AggregateAllRequest {
             proof_id: agg_all_task.proof_id.clone(),
             computed_request_id: agg_all_task.task_id.clone(),
@@ -238,6 +452,7 @@ pub async fn aggregate_all(
             proof_num: agg_all_task.proof_num,
             receipt_dir: agg_all_task.receipt_dir.clone(),
             output_dir: agg_all_task.output_dir.clone(),
+            agg_receipt_path: agg_all_task.agg_receipt_path.clone(),
         };
         log::info!(
             "[aggregate_all] rpc {}:{} start",
@@ -245,18 +460,36 @@ pub async fn aggregate_all(
             request.computed_request_id
         );
         log::debug!("aggregate_all request {:#?}", request);
+        track_task(&agg_all_task.proof_id, &agg_all_task.task_id, &addrs);
         let mut grpc_request = Request::new(request);
-        grpc_request.set_timeout(Duration::from_secs(TASK_TIMEOUT));
-        let response = client.aggregate_all(grpc_request).await;
-        if let Ok(response) = response {
-            if let Some(response_result) = response.get_ref().result.as_ref() {
+        grpc_request.set_timeout(Duration::from_secs(JOB_SUBMIT_TIMEOUT));
+        let response = match client.aggregate_all(grpc_request).await {
+            Ok(response) => {
+                let accepted = response.into_inner().result;
+                wait_task_result(
+                    &mut client,
+                    &addrs,
+                    &agg_all_task.proof_id,
+                    &agg_all_task.task_id,
+                    accepted,
+                )
+                .await
+            }
+            Err(_) => None,
+        };
+        untrack_task(&agg_all_task.task_id, &addrs);
+        if let Some(response) = response {
+            if let Some(response_result) = response.result.as_ref() {
                 log::debug!("aggregate_all response {:#?}", response);
                 agg_all_task.state = result_code_to_state(response_result.code);
                 agg_all_task.node_info = addrs;
+                if agg_all_task.state == TASK_STATE_FAILED {
+                    agg_all_task.errmsg.clone_from(&response_result.message);
+                }
                 log::info!(
                     "[aggregate_all] rpc {}:{}  code:{:?} message:{:?}",
-                    response.get_ref().proof_id,
-                    response.get_ref().computed_request_id,
+                    response.proof_id,
+                    response.computed_request_id,
                     response_result.code,
                     response_result.message,
                 );
@@ -268,10 +501,79 @@ pub async fn aggregate_all(
     Some(agg_all_task)
 }
 
+pub async fn wrap(mut wrap_task: WrapTask, _tls_config: Option<TlsConfig>) -> Option<WrapTask> {
+    wrap_task.state = TASK_STATE_UNPROCESSED;
+    wrap_task.node_info.clear();
+    let client = scheduler::acquire(
+        &wrap_task.proof_id,
+        TASK_ITYPE_WRAP,
+        wrap_task.seg_size,
+        &wrap_task.excluded_nodes,
+    )
+    .await;
+    if let Some((addrs, mut client, _lease)) = client {
+        let request = WrapRequest {
+            proof_id: wrap_task.proof_id.clone(),
+            computed_request_id: wrap_task.task_id.clone(),
+            base_dir: wrap_task.base_dir.clone(),
+            block_no: wrap_task.block_no,
+            seg_size: wrap_task.seg_size,
+            agg_receipt_path: wrap_task.agg_receipt_path.clone(),
+            output_dir: wrap_task.output_dir.clone(),
+        };
+        log::info!(
+            "[wrap] rpc {}:{} {} start",
+            request.proof_id,
+            request.computed_request_id,
+            request.agg_receipt_path,
+        );
+        log::debug!("wrap request {:#?}", request);
+        track_task(&wrap_task.proof_id, &wrap_task.task_id, &addrs);
+        let mut grpc_request = Request::new(request);
+        grpc_request.set_timeout(Duration::from_secs(JOB_SUBMIT_TIMEOUT));
+        let response = match client.wrap(grpc_request).await {
+            Ok(response) => {
+                let accepted = response.into_inner().result;
+                wait_task_result(
+                    &mut client,
+                    &addrs,
+                    &wrap_task.proof_id,
+                    &wrap_task.task_id,
+                    accepted,
+                )
+                .await
+            }
+            Err(_) => None,
+        };
+        untrack_task(&wrap_task.task_id, &addrs);
+        if let Some(response) = response {
+            if let Some(response_result) = response.result.as_ref() {
+                log::debug!("wrap response {:#?}", response);
+                wrap_task.state = result_code_to_state(response_result.code);
+                wrap_task.node_info = addrs;
+                if wrap_task.state == TASK_STATE_FAILED {
+                    wrap_task.errmsg.clone_from(&response_result.message);
+                }
+                log::info!(
+                    "[wrap] rpc {}:{} code:{:?} message:{:?} end",
+                    response.proof_id,
+                    response.computed_request_id,
+                    response_result.code,
+                    response_result.message,
+                );
+                return Some(wrap_task);
+            }
+        }
+    }
+    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    Some(wrap_task)
+}
+
 pub async fn final_proof(
     mut final_task: FinalTask,
     _tls_config: Option<TlsConfig>,
 ) -> Option<FinalTask> {
+    final_task.node_info.clear();
     let client = get_snark_client(None).await;
     if let Some((addrs, mut client)) = client {
         let (
@@ -349,13 +651,17 @@ pub async fn final_proof(
                         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
                         loop_count += 1;
                         if loop_count > TASK_TIMEOUT {
+                            final_task.errmsg = "timeout".to_string();
                             break;
                         }
                     }
+                } else {
+                    final_task.errmsg.clone_from(&response_result.message);
                 }
             }
         }
         final_task.state = TASK_STATE_FAILED;
+        final_task.node_info = addrs;
     } else {
         final_task.state = TASK_STATE_UNPROCESSED;
     }
@@ -363,6 +669,56 @@ pub async fn final_proof(
     Some(final_task)
 }
 
+pub async fn cancel_tasks(proof_id: &str) {
+    let tasks: Vec<(String, String)> = RUNNING_TASKS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, task_proof_id)| *task_proof_id == proof_id)
+        .map(|(key, _)| key.clone())
+        .collect();
+    send_cancel(proof_id, tasks).await;
+}
+
+/// Cancel the copies of a task that are still running after one of them finished
+pub async fn cancel_copies(proof_id: &str, task_id: &str) {
+    let tasks: Vec<(String, String)> = RUNNING_TASKS
+        .lock()
+        .unwrap()
+        .keys()
+        .filter(|(running_id, _)| original_task_id(running_id) == original_task_id(task_id))
+        .cloned()
+        .collect();
+    send_cancel(proof_id, tasks).await;
+}
+
+async fn send_cancel(proof_id: &str, tasks: Vec<(String, String)>) {
+    let nodes = get_nodes();
+    for (task_id, addrs) in tasks {
+        let channel = nodes
+            .iter()
+            .find(|node| node.addr == addrs)
+            .and_then(|node| node.get_client());
+        if let Some(channel) = channel {
+            let mut client = ProverServiceClient::<Channel>::new(channel);
+            let request = CancelTaskRequest {
+                proof_id: proof_id.to_owned(),
+                computed_request_id: task_id.clone(),
+            };
+            let mut grpc_request = Request::new(request);
+            grpc_request.set_timeout(Duration::from_secs(30));
+            let response = client.cancel_task(grpc_request).await;
+            log::info!(
+                "[cancel_task] rpc {}:{} node:{} ok:{}",
+                proof_id,
+                task_id,
+                addrs,
+                response.is_ok()
+            );
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub async fn get_task_status(
     client: &mut ProverServiceClient<Channel>,
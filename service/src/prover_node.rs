@@ -1,30 +1,160 @@
 use crate::prover_client::prover_service::get_status_response;
 use crate::prover_client::prover_service::prover_service_client::ProverServiceClient;
-use crate::prover_client::prover_service::GetStatusRequest;
+use crate::prover_client::prover_service::{GetStatusRequest, GetStatusResponse};
 use common::tls::Config as TlsConfig;
 use once_cell::sync::OnceCell;
-use stage::tasks::TASK_TIMEOUT;
+use serde_derive::Deserialize;
+use stage::tasks::{
+    TASK_ITYPE_AGG, TASK_ITYPE_AGGALL, TASK_ITYPE_FINAL, TASK_ITYPE_PROVE, TASK_ITYPE_SPLIT,
+    TASK_ITYPE_WRAP, TASK_TIMEOUT,
+};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tonic::transport::Channel;
 use tonic::transport::ClientTlsConfig;
+use tonic::transport::Endpoint;
 use tonic::transport::Uri;
 use tonic::Request;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoryClass {
+    Small,
+    Medium,
+    #[default]
+    Large,
+}
+
+impl MemoryClass {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "small" => Some(MemoryClass::Small),
+            "medium" => Some(MemoryClass::Medium),
+            "large" | "" => Some(MemoryClass::Large),
+            _ => None,
+        }
+    }
+}
+
+/// Role of a node for each task type
+pub fn role_of(itype: i32) -> &'static str {
+    match itype {
+        TASK_ITYPE_SPLIT => "split",
+        TASK_ITYPE_PROVE => "root",
+        TASK_ITYPE_AGG => "agg",
+        TASK_ITYPE_AGGALL => "agg_all",
+        TASK_ITYPE_WRAP => "wrap",
+        TASK_ITYPE_FINAL => "final",
+        _ => "",
+    }
+}
+
+/// What a node takes, by default every task of any segment size
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct NodeCapabilities {
+    // split, root, agg, agg_all, final. empty for all of them
+    pub roles: Vec<String>,
+    // 0 for no limit
+    pub max_seg_size: u32,
+    pub memory_class: MemoryClass,
+}
+
+impl NodeCapabilities {
+    pub fn can_run(&self, itype: i32, seg_size: u32, memory_class: MemoryClass) -> bool {
+        (self.roles.is_empty() || self.roles.iter().any(|role| role == role_of(itype)))
+            && (self.max_seg_size == 0 || seg_size <= self.max_seg_size)
+            && self.memory_class >= memory_class
+    }
+}
+
+/// Smallest memory class configured for the role of a task type
+pub fn min_memory_class(itype: i32) -> MemoryClass {
+    crate::config::instance()
+        .lock()
+        .unwrap()
+        .min_memory_class
+        .as_ref()
+        .and_then(|classes| classes.get(role_of(itype)).copied())
+        .unwrap_or(MemoryClass::Small)
+}
+
+/// Host info reported by the node, as of its last status
+#[derive(Debug, Clone, Default)]
+pub struct NodeInfo {
+    pub prover_name: String,
+    pub prover_id: String,
+    pub number_of_cores: u64,
+    // bytes
+    pub total_memory: u64,
+    pub free_memory: u64,
+    pub updated_at: u64,
+    // the whole last status, None until the node answered once
+    pub status: Option<GetStatusResponse>,
+}
+
+/// Endpoint of a node or the stage, with client TLS when configured
+pub fn endpoint(addr: &str, tls_config: Option<TlsConfig>) -> Endpoint {
+    let uri = format!("grpc://{}", addr).parse::<Uri>().unwrap();
+    let mut endpoint = tonic::transport::Channel::builder(uri)
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(TASK_TIMEOUT))
+        .concurrency_limit(256);
+    if let Some(config) = tls_config {
+        let mut tls_config = ClientTlsConfig::new();
+        if let Some(ca_cert) = config.ca_cert {
+            tls_config = tls_config.ca_certificate(ca_cert);
+        }
+        if let Some(identity) = config.identity {
+            tls_config = tls_config.identity(identity);
+        }
+        endpoint = endpoint.tls_config(tls_config).unwrap();
+    }
+    endpoint
+}
+
 #[derive(Debug, Clone)]
 pub struct ProverNode {
     pub addr: String,
     pub client: Arc<Mutex<Option<tonic::transport::channel::Channel>>>,
+    pub info: Arc<Mutex<NodeInfo>>,
+    pub capabilities: NodeCapabilities,
 }
 
 impl ProverNode {
     pub fn new(addr: &String) -> Self {
+        Self::with_capabilities(addr, NodeCapabilities::default())
+    }
+
+    pub fn with_capabilities(addr: &str, capabilities: NodeCapabilities) -> Self {
         ProverNode {
             addr: addr.to_string(),
             client: Arc::new(Mutex::new(None)),
+            info: Arc::new(Mutex::new(NodeInfo::default())),
+            capabilities,
         }
     }
 
+    pub fn get_info(&self) -> NodeInfo {
+        self.info.lock().unwrap().clone()
+    }
+
+    fn set_info(&self, response: &GetStatusResponse) {
+        *self.info.lock().unwrap() = NodeInfo {
+            prover_name: response.prover_name.clone(),
+            prover_id: response.prover_id.clone(),
+            number_of_cores: response.number_of_cores,
+            total_memory: response.total_memory,
+            free_memory: response.free_memory,
+            updated_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            status: Some(response.clone()),
+        };
+    }
+
     pub fn get_client(&self) -> Option<tonic::transport::channel::Channel> {
         self.client.lock().unwrap().clone()
     }
@@ -33,30 +163,16 @@ impl ProverNode {
         *self.client.lock().unwrap() = client;
     }
 
-    pub async fn is_active(
+    /// Query the node status, None when it can not be reached
+    pub async fn get_status(
         &mut self,
         tls_config: Option<TlsConfig>,
-    ) -> Option<ProverServiceClient<Channel>> {
+    ) -> Option<(ProverServiceClient<Channel>, GetStatusResponse)> {
         let mut client = self.get_client();
         match client {
             Some(_) => {}
             None => {
-                let uri = format!("grpc://{}", self.addr).parse::<Uri>().unwrap();
-                let mut endpoint = tonic::transport::Channel::builder(uri)
-                    .connect_timeout(Duration::from_secs(5))
-                    .timeout(Duration::from_secs(TASK_TIMEOUT))
-                    .concurrency_limit(256);
-                if let Some(config) = tls_config {
-                    let mut tls_config = ClientTlsConfig::new();
-                    if let Some(ca_cert) = config.ca_cert {
-                        tls_config = tls_config.ca_certificate(ca_cert);
-                    }
-                    if let Some(identity) = config.identity {
-                        tls_config = tls_config.identity(identity);
-                    }
-                    endpoint = endpoint.tls_config(tls_config).unwrap();
-                }
-                let client_init = endpoint.connect().await;
+                let client_init = endpoint(&self.addr, tls_config).connect().await;
                 if let Ok(client_init) = client_init {
                     self.set_client(Some(client_init.clone()));
                     client = Some(client_init.clone());
@@ -69,26 +185,52 @@ impl ProverNode {
             let request = GetStatusRequest {};
             let response = client.get_status(Request::new(request)).await;
             if let Ok(response) = response {
-                let status = response.get_ref().status;
-                if get_status_response::Status::from_i32(status)
-                    == Some(get_status_response::Status::Idle)
-                    || get_status_response::Status::from_i32(status)
-                        == Some(get_status_response::Status::Unspecified)
-                {
-                    return Some(client);
-                }
+                self.set_info(response.get_ref());
+                return Some((client, response.into_inner()));
             } else {
                 self.set_client(None);
             }
         }
         None
     }
+
+    pub async fn is_active(
+        &mut self,
+        tls_config: Option<TlsConfig>,
+    ) -> Option<ProverServiceClient<Channel>> {
+        let (client, response) = self.get_status(tls_config).await?;
+        match get_status_response::Status::from_i32(response.status) {
+            Some(get_status_response::Status::Idle)
+            | Some(get_status_response::Status::Unspecified) => Some(client),
+            // a halted node is draining and takes no new jobs
+            Some(get_status_response::Status::Halt) => {
+                log::info!("[is_active] {} halted", self.addr);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Seconds between two heartbeats of a registered node
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 10;
+/// A registered node is dropped after this many seconds without a heartbeat
+pub const NODE_TIMEOUT_SECS: u64 = 3 * HEARTBEAT_INTERVAL_SECS;
+
+fn now_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
 
 #[derive(Debug)]
 pub struct ProverNodes {
     pub prover_nodes: Vec<ProverNode>,
     pub snark_nodes: Vec<ProverNode>,
+    // addr => last heartbeat of the nodes registered through RegisterNode, the configured
+    // nodes are never dropped
+    heartbeats: HashMap<String, u64>,
 }
 
 static INSTANCE: OnceCell<Mutex<ProverNodes>> = OnceCell::new();
@@ -102,6 +244,7 @@ impl ProverNodes {
         ProverNodes {
             prover_nodes: Vec::new(),
             snark_nodes: Vec::new(),
+            heartbeats: HashMap::new(),
         }
     }
     pub fn add_node(&mut self, node: ProverNode) {
@@ -119,4 +262,102 @@ impl ProverNodes {
     pub fn get_snark_nodes(&self) -> Vec<ProverNode> {
         self.snark_nodes.clone()
     }
+
+    pub fn contains(&self, addr: &str) -> bool {
+        self.prover_nodes
+            .iter()
+            .chain(self.snark_nodes.iter())
+            .any(|node| node.addr == addr)
+    }
+
+    /// Add a node that announced itself, false when it was already there
+    pub fn register_node(
+        &mut self,
+        addr: &str,
+        snark: bool,
+        capabilities: NodeCapabilities,
+    ) -> bool {
+        let configured = !self.heartbeats.contains_key(addr) && self.contains(addr);
+        if !configured {
+            self.heartbeats.insert(addr.to_string(), now_timestamp());
+        }
+        if self.contains(addr) {
+            return false;
+        }
+        let node = ProverNode::with_capabilities(addr, capabilities);
+        if snark {
+            self.add_snark_node(node);
+        } else {
+            self.add_node(node);
+        }
+        true
+    }
+
+    /// Record a heartbeat, false when the node is not registered
+    pub fn heartbeat(&mut self, addr: &str) -> bool {
+        match self.heartbeats.get_mut(addr) {
+            Some(heartbeat) => {
+                *heartbeat = now_timestamp();
+                true
+            }
+            None => self.contains(addr),
+        }
+    }
+
+    /// Drop the registered nodes that missed their heartbeats
+    pub fn drop_expired(&mut self) -> Vec<String> {
+        let now = now_timestamp();
+        let expired: Vec<String> = self
+            .heartbeats
+            .iter()
+            .filter(|(_, heartbeat)| **heartbeat + NODE_TIMEOUT_SECS < now)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in &expired {
+            self.heartbeats.remove(addr);
+            self.prover_nodes.retain(|node| &node.addr != addr);
+            self.snark_nodes.retain(|node| &node.addr != addr);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heartbeat_expiry() {
+        let mut nodes = ProverNodes::new();
+        nodes.add_node(ProverNode::new(&"127.0.0.1:50000".to_string()));
+        let addr = "127.0.0.1:50001";
+        assert!(nodes.register_node(addr, false, NodeCapabilities::default()));
+        assert!(!nodes.register_node(addr, false, NodeCapabilities::default()));
+        assert!(nodes.register_node("127.0.0.1:50002", true, NodeCapabilities::default()));
+        // a configured node registering keeps no heartbeat
+        assert!(!nodes.register_node("127.0.0.1:50000", false, NodeCapabilities::default()));
+        assert!(nodes.heartbeat(addr));
+        assert!(nodes.heartbeat("127.0.0.1:50000"));
+        assert!(!nodes.heartbeat("127.0.0.1:50003"));
+        assert!(nodes.drop_expired().is_empty());
+
+        // a missed heartbeat or two is still in time
+        let now = now_timestamp();
+        nodes
+            .heartbeats
+            .insert(addr.to_string(), now - NODE_TIMEOUT_SECS + 5);
+        assert!(nodes.drop_expired().is_empty());
+
+        for heartbeat in nodes.heartbeats.values_mut() {
+            *heartbeat = now - NODE_TIMEOUT_SECS - 2;
+        }
+        let mut expired = nodes.drop_expired();
+        expired.sort();
+        assert_eq!(expired, vec!["127.0.0.1:50001", "127.0.0.1:50002"]);
+        assert!(!nodes.contains(addr));
+        assert!(!nodes.contains("127.0.0.1:50002"));
+        assert!(nodes.contains("127.0.0.1:50000"));
+        assert!(!nodes.heartbeat(addr));
+        assert!(nodes.drop_expired().is_empty());
+    }
 }
@@ -1,21 +1,36 @@
 use executor::split_context::SplitContext;
-use prover::contexts::{AggAllContext, AggContext, ProveContext};
+use prover::contexts::{AggAllContext, AggContext, ProveContext, WrapContext};
 use prover::pipeline::Pipeline;
 use prover_service::prover_service_server::ProverService;
 use prover_service::{get_status_response, GetStatusRequest, GetStatusResponse};
 use prover_service::{AggregateAllRequest, AggregateAllResponse};
 use prover_service::{AggregateRequest, AggregateResponse};
+use prover_service::{CancelTaskRequest, CancelTaskResponse};
+use prover_service::{DrainRequest, DrainResponse};
 use prover_service::{FinalProofRequest, FinalProofResponse};
 use prover_service::{GetTaskResultRequest, GetTaskResultResponse, Result};
 use prover_service::{ProveRequest, ProveResponse};
-use prover_service::{SplitElfRequest, SplitElfResponse};
+use prover_service::{SplitElfRequest, SplitElfResponse, TaskState};
+use prover_service::{WrapRequest, WrapResponse};
+use stage::tasks::{
+    TASK_ITYPE_AGG, TASK_ITYPE_AGGALL, TASK_ITYPE_PROVE, TASK_ITYPE_SPLIT, TASK_ITYPE_WRAP,
+};
 use std::time::Instant;
+use tokio::sync::Semaphore;
 use tonic::{Request, Response, Status};
 
 use self::prover_service::ResultCode;
 
+use crate::config;
 use crate::metrics;
+use lazy_static::lazy_static;
+use once_cell::sync::OnceCell;
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
 use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use sysinfo::System;
 
 #[allow(clippy::module_inception)]
 pub mod prover_service {
@@ -47,35 +62,294 @@ async fn run_back_task<
     }
 }
 
-#[derive(Debug, Default)]
-pub struct ProverServiceSVC {}
+// finished jobs are kept this long for the stage to fetch their result
+const JOB_RETENTION_SECS: u64 = 3600;
+// a draining node waits at most this long for the stage to fetch the last results
+const DRAIN_FETCH_SECS: u64 = 60;
 
-macro_rules! on_done {
-    ($result:ident, $resp:ident) => {
-        match $result {
-            Ok(done) => {
-                if done {
-                    $resp.result = Some(Result {
-                        code: (ResultCode::Ok.into()),
-                        message: ("SUCCESS".to_string()),
-                    });
-                } else {
-                    $resp.result = Some(Result {
-                        code: (ResultCode::Busy.into()),
-                        message: ("BUSY".to_string()),
-                    });
-                }
-            }
-            Err(e) => {
-                $resp.result = Some(Result {
-                    code: (ResultCode::InternalError.into()),
-                    message: (e.to_string()),
-                });
+struct Job {
+    proof_id: String,
+    state: TaskState,
+    result: Result,
+    total_steps: u64,
+    finish_ts: u64,
+    // the stage got the final result
+    fetched: bool,
+}
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Put the node in drain mode: it reports `STATUS_HALT`, turns new jobs away as busy and
+/// exits once the running jobs are done and their results fetched, see `drained`
+pub fn drain() {
+    if !DRAINING.swap(true, Ordering::Relaxed) {
+        log::info!("draining, {} jobs pending", pending_jobs());
+    }
+}
+
+fn pending_jobs() -> usize {
+    JOBS.lock()
+        .unwrap()
+        .values()
+        .filter(|job| job.state == TaskState::Queued || job.state == TaskState::Running)
+        .count()
+}
+
+/// Resolves once the node is drained
+pub async fn drained() {
+    loop {
+        if DRAINING.load(Ordering::Relaxed) {
+            let now = now_timestamp();
+            let busy = JOBS.lock().unwrap().values().any(|job| {
+                job.finish_ts == 0 || (!job.fetched && job.finish_ts + DRAIN_FETCH_SECS > now)
+            });
+            if !busy {
+                log::info!("drained");
+                return;
             }
         }
-    };
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    }
+}
+
+lazy_static! {
+    static ref CANCEL_FLAGS: Mutex<HashMap<String, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+    // computed_request_id => job
+    static ref JOBS: Mutex<HashMap<String, Job>> = Mutex::new(HashMap::new());
+    static ref SYSTEM: Mutex<System> = Mutex::new(System::new());
+}
+
+static IDENTITY: OnceCell<(String, String)> = OnceCell::new();
+
+/// Name and id of the node. The id is derived from the host name and listen address, so it
+/// stays the same across restarts.
+fn identity() -> &'static (String, String) {
+    IDENTITY.get_or_init(|| {
+        let config = config::instance().lock().unwrap();
+        let host_name = System::host_name().unwrap_or_default();
+        let prover_name = config.prover_name.clone().unwrap_or(host_name.clone());
+        let digest = Keccak256::digest(format!("{}@{}", host_name, config.addr).as_bytes());
+        (prover_name, hex::encode(&digest[..8]))
+    })
+}
+
+fn fill_host_info(response: &mut GetStatusResponse) {
+    let (prover_name, prover_id) = identity();
+    response.prover_name.clone_from(prover_name);
+    response.prover_id.clone_from(prover_id);
+    response.number_of_cores = std::thread::available_parallelism()
+        .map(|cores| cores.get() as u64)
+        .unwrap_or_default();
+    let mut system = SYSTEM.lock().unwrap();
+    system.refresh_memory();
+    response.total_memory = system.total_memory();
+    response.free_memory = system.available_memory();
+}
+
+/// A job holds a slot of its type while it runs, the others stay queued
+struct Slots {
+    limits: config::JobLimits,
+    split: Semaphore,
+    prove: Semaphore,
+    agg: Semaphore,
+    agg_all: Semaphore,
+    wrap: Semaphore,
+}
+
+static SLOTS: OnceCell<Slots> = OnceCell::new();
+
+fn slots() -> &'static Slots {
+    SLOTS.get_or_init(|| {
+        let limits = config::instance()
+            .lock()
+            .unwrap()
+            .job_limits
+            .clone()
+            .unwrap_or_default();
+        Slots {
+            split: Semaphore::new(limits.split as usize),
+            prove: Semaphore::new(limits.prove as usize),
+            agg: Semaphore::new(limits.agg as usize),
+            agg_all: Semaphore::new(limits.agg_all as usize),
+            wrap: Semaphore::new(limits.wrap as usize),
+            limits,
+        }
+    })
+}
+
+impl Slots {
+    fn get(&self, itype: i32) -> &Semaphore {
+        match itype {
+            TASK_ITYPE_SPLIT => &self.split,
+            TASK_ITYPE_PROVE => &self.prove,
+            TASK_ITYPE_AGG => &self.agg,
+            TASK_ITYPE_WRAP => &self.wrap,
+            _ => &self.agg_all,
+        }
+    }
+
+    fn total(&self) -> prover_service::JobSlots {
+        prover_service::JobSlots {
+            split: self.limits.split,
+            prove: self.limits.prove,
+            agg: self.limits.agg,
+            agg_all: self.limits.agg_all,
+            wrap: self.limits.wrap,
+        }
+    }
+
+    fn free(&self) -> prover_service::JobSlots {
+        prover_service::JobSlots {
+            split: self.split.available_permits() as u32,
+            prove: self.prove.available_permits() as u32,
+            agg: self.agg.available_permits() as u32,
+            agg_all: self.agg_all.available_permits() as u32,
+            wrap: self.wrap.available_permits() as u32,
+        }
+    }
 }
 
+fn now_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn register_task(computed_request_id: &str) -> Arc<AtomicBool> {
+    let flag = Arc::new(AtomicBool::new(false));
+    CANCEL_FLAGS
+        .lock()
+        .unwrap()
+        .insert(computed_request_id.to_string(), flag.clone());
+    flag
+}
+
+fn finish_task<T>(
+    computed_request_id: &str,
+    result: std::result::Result<T, String>,
+) -> std::result::Result<T, String> {
+    let flag = CANCEL_FLAGS.lock().unwrap().remove(computed_request_id);
+    match flag {
+        Some(flag) if flag.load(Ordering::Relaxed) => Err("cancelled".to_string()),
+        _ => result,
+    }
+}
+
+fn job_result(result: &std::result::Result<bool, String>) -> Result {
+    match result {
+        Ok(true) => Result {
+            code: ResultCode::Ok.into(),
+            message: "SUCCESS".to_string(),
+        },
+        Ok(false) => Result {
+            code: ResultCode::Busy.into(),
+            message: "BUSY".to_string(),
+        },
+        Err(e) => Result {
+            code: ResultCode::InternalError.into(),
+            message: e.to_string(),
+        },
+    }
+}
+
+fn pending_result(state: TaskState) -> Result {
+    Result {
+        code: ResultCode::Unspecified.into(),
+        message: state.as_str_name().to_string(),
+    }
+}
+
+fn set_job_state(computed_request_id: &str, state: TaskState) {
+    if let Some(job) = JOBS.lock().unwrap().get_mut(computed_request_id) {
+        job.state = state;
+        job.result = pending_result(state);
+    }
+}
+
+/// Queue a job and return at once, the stage polls `GetTaskResult` for its result. A request
+/// id that is already queued, running or done is not run again, only a failed job is.
+fn submit_job<F>(
+    name: &'static str,
+    itype: i32,
+    proof_id: &str,
+    computed_request_id: &str,
+    callable: F,
+) -> (Result, u64)
+where
+    F: FnOnce(Arc<AtomicBool>) -> std::result::Result<(bool, u64), String> + Send + 'static,
+{
+    let mut jobs = JOBS.lock().unwrap();
+    let now = now_timestamp();
+    jobs.retain(|_, job| job.finish_ts == 0 || job.finish_ts + JOB_RETENTION_SECS > now);
+    if let Some(job) = jobs.get(computed_request_id) {
+        if job.state != TaskState::Failed {
+            log::info!(
+                "[{}] {}:{} already {}",
+                name,
+                proof_id,
+                computed_request_id,
+                job.state.as_str_name()
+            );
+            return (job.result.clone(), job.total_steps);
+        }
+    }
+    if DRAINING.load(Ordering::Relaxed) {
+        log::info!("[{}] {}:{} draining", name, proof_id, computed_request_id);
+        return (job_result(&Ok(false)), 0);
+    }
+    jobs.insert(
+        computed_request_id.to_string(),
+        Job {
+            proof_id: proof_id.to_string(),
+            state: TaskState::Queued,
+            result: pending_result(TaskState::Queued),
+            total_steps: 0,
+            finish_ts: 0,
+            fetched: false,
+        },
+    );
+    let cancelled = register_task(computed_request_id);
+    let proof_id = proof_id.to_string();
+    let computed_request_id = computed_request_id.to_string();
+    tokio::spawn(async move {
+        let _permit = slots().get(itype).acquire().await.unwrap();
+        set_job_state(&computed_request_id, TaskState::Running);
+        let start = Instant::now();
+        // a job cancelled while queued gives up its slot without running
+        let result = if cancelled.load(Ordering::Relaxed) {
+            Err("cancelled".to_string())
+        } else {
+            run_back_task(move || callable(cancelled)).await
+        };
+        let result = finish_task(&computed_request_id, result);
+        let total_steps = result.as_ref().map(|r| r.1).unwrap_or_default();
+        let result = job_result(&result.map(|r| r.0));
+        log::info!(
+            "[{}] {}:{} code:{} elapsed:{} end",
+            name,
+            proof_id,
+            computed_request_id,
+            result.code,
+            start.elapsed().as_secs()
+        );
+        if let Some(job) = JOBS.lock().unwrap().get_mut(&computed_request_id) {
+            job.state = if result.code == ResultCode::Ok as i32 {
+                TaskState::Done
+            } else {
+                TaskState::Failed
+            };
+            job.result = result;
+            job.total_steps = total_steps;
+            job.finish_ts = now_timestamp();
+        }
+    });
+    (pending_result(TaskState::Queued), 0)
+}
+
+#[derive(Debug, Default)]
+pub struct ProverServiceSVC {}
+
 #[tonic::async_trait]
 impl ProverService for ProverServiceSVC {
     async fn get_status(
@@ -86,8 +360,19 @@ impl ProverService for ProverServiceSVC {
             // log::info!("{:#?}", request);
 
             let mut response = prover_service::GetStatusResponse::default();
-            let success = Pipeline::new().get_status();
-            if success {
+            let pending = JOBS
+                .lock()
+                .unwrap()
+                .values()
+                .any(|job| job.state == TaskState::Queued || job.state == TaskState::Running);
+            fill_host_info(&mut response);
+            response.total_slots = Some(slots().total());
+            response.free_slots = Some(slots().free());
+            if DRAINING.load(Ordering::Relaxed) {
+                response.status = get_status_response::Status::Halt.into();
+            } else if !prover::provers::is_ready() {
+                response.status = get_status_response::Status::Booting.into();
+            } else if !pending {
                 response.status = get_status_response::Status::Idle.into();
             } else {
                 response.status = get_status_response::Status::Computing.into();
@@ -99,11 +384,33 @@ impl ProverService for ProverServiceSVC {
 
     async fn get_task_result(
         &self,
-        _request: Request<GetTaskResultRequest>,
+        request: Request<GetTaskResultRequest>,
     ) -> tonic::Result<Response<GetTaskResultResponse>, Status> {
         metrics::record_metrics("prover::get_task_result", || async {
-            // log::info!("{:#?}", request);
-            let response = prover_service::GetTaskResultResponse::default();
+            let mut response = prover_service::GetTaskResultResponse {
+                proof_id: request.get_ref().proof_id.clone(),
+                computed_request_id: request.get_ref().computed_request_id.clone(),
+                ..Default::default()
+            };
+            match JOBS
+                .lock()
+                .unwrap()
+                .get_mut(&request.get_ref().computed_request_id)
+            {
+                Some(job) if job.proof_id == request.get_ref().proof_id => {
+                    job.fetched = job.finish_ts > 0;
+                    response.state = job.state.into();
+                    response.result = Some(job.result.clone());
+                    response.total_steps = job.total_steps;
+                }
+                _ => {
+                    response.state = TaskState::Unknown.into();
+                    response.result = Some(Result {
+                        code: ResultCode::InvalidParameter.into(),
+                        message: "task not found".to_string(),
+                    });
+                }
+            }
             Ok(Response::new(response))
         })
         .await
@@ -120,7 +427,6 @@ impl ProverService for ProverServiceSVC {
                 request.get_ref().computed_request_id,
             );
             log::debug!("{:#?}", request);
-            let start = Instant::now();
 
             let split_context = SplitContext::new(
                 &request.get_ref().base_dir,
@@ -134,31 +440,25 @@ impl ProverService for ProverServiceSVC {
                 &request.get_ref().args,
                 &request.get_ref().receipt_inputs_path,
             );
-            let split_func = move || {
+            let split_func = move |cancelled| {
                 let s_ctx: SplitContext = split_context;
-                executor::executor::Executor::new().split(&s_ctx)
+                executor::executor::Executor::with_cancel_flag(cancelled)
+                    .split(&s_ctx)
+                    .map(|cycle| (cycle > 0, cycle))
             };
-            let result = run_back_task(split_func).await;
-            let mut response = prover_service::SplitElfResponse {
+            let (result, total_steps) = submit_job(
+                "split_elf",
+                TASK_ITYPE_SPLIT,
+                &request.get_ref().proof_id,
+                &request.get_ref().computed_request_id,
+                split_func,
+            );
+            let response = prover_service::SplitElfResponse {
                 proof_id: request.get_ref().proof_id.clone(),
                 computed_request_id: request.get_ref().computed_request_id.clone(),
-                total_steps: result.clone().unwrap_or_default(),
-                ..Default::default()
-            };
-            let result = match result {
-                Ok(cycle) => Ok(cycle > 0),
-                Err(e) => Err(e),
+                result: Some(result),
+                total_steps,
             };
-            on_done!(result, response);
-            let end = Instant::now();
-            let elapsed = end.duration_since(start);
-            log::info!(
-                "[split_elf] {}:{} code:{} elapsed:{} end",
-                request.get_ref().proof_id,
-                request.get_ref().computed_request_id,
-                response.result.as_ref().unwrap().code,
-                elapsed.as_secs()
-            );
             Ok(Response::new(response))
         })
         .await
@@ -176,7 +476,6 @@ impl ProverService for ProverServiceSVC {
                 request.get_ref().seg_path,
             );
             log::debug!("{:#?}", request);
-            let start = Instant::now();
 
             let prove_context = ProveContext::new(
                 &request.get_ref().base_dir,
@@ -187,26 +486,24 @@ impl ProverService for ProverServiceSVC {
                 &request.get_ref().receipts_path,
             );
 
-            let prove_func = move || {
+            let prove_func = move |cancelled| {
                 let s_ctx: ProveContext = prove_context;
-                Pipeline::new().prove_root(&s_ctx)
+                Pipeline::with_cancel_flag(cancelled)
+                    .prove_root(&s_ctx)
+                    .map(|done| (done, 0))
             };
-            let result = run_back_task(prove_func).await;
-            let mut response = prover_service::ProveResponse {
+            let (result, _) = submit_job(
+                "prove",
+                TASK_ITYPE_PROVE,
+                &request.get_ref().proof_id,
+                &request.get_ref().computed_request_id,
+                prove_func,
+            );
+            let response = prover_service::ProveResponse {
                 proof_id: request.get_ref().proof_id.clone(),
                 computed_request_id: request.get_ref().computed_request_id.clone(),
-                ..Default::default()
+                result: Some(result),
             };
-            on_done!(result, response);
-            let end = Instant::now();
-            let elapsed = end.duration_since(start);
-            log::info!(
-                "[prove] {}:{} code:{} elapsed:{} end",
-                request.get_ref().proof_id,
-                request.get_ref().computed_request_id,
-                response.result.as_ref().unwrap().code,
-                elapsed.as_secs()
-            );
             Ok(Response::new(response))
         })
         .await
@@ -235,7 +532,6 @@ impl ProverService for ProverServiceSVC {
                     .receipt_path,
             );
             log::debug!("{:#?}", request);
-            let start = Instant::now();
             let input1 = request.get_ref().input1.clone().expect("need input1");
             let input2 = request.get_ref().input2.clone().expect("need input2");
             let agg_context = AggContext::new(
@@ -251,26 +547,24 @@ impl ProverService for ProverServiceSVC {
                 &request.get_ref().output_dir,
             );
 
-            let agg_func = move || {
+            let agg_func = move |cancelled| {
                 let agg_ctx = agg_context;
-                Pipeline::new().prove_aggregate(&agg_ctx)
+                Pipeline::with_cancel_flag(cancelled)
+                    .prove_aggregate(&agg_ctx)
+                    .map(|done| (done, 0))
             };
-            let result = run_back_task(agg_func).await;
-            let mut response = prover_service::AggregateResponse {
+            let (result, _) = submit_job(
+                "aggregate",
+                TASK_ITYPE_AGG,
+                &request.get_ref().proof_id,
+                &request.get_ref().computed_request_id,
+                agg_func,
+            );
+            let response = prover_service::AggregateResponse {
                 proof_id: request.get_ref().proof_id.clone(),
                 computed_request_id: request.get_ref().computed_request_id.clone(),
-                ..Default::default()
+                result: Some(result),
             };
-            on_done!(result, response);
-            let end = Instant::now();
-            let elapsed = end.duration_since(start);
-            log::info!(
-                "[aggregate] {}:{} code:{} elapsed:{} end",
-                request.get_ref().proof_id,
-                request.get_ref().computed_request_id,
-                response.result.as_ref().unwrap().code,
-                elapsed.as_secs()
-            );
             Ok(Response::new(response))
         })
         .await
@@ -287,7 +581,6 @@ impl ProverService for ProverServiceSVC {
                 request.get_ref().computed_request_id,
             );
             log::debug!("{:#?}", request);
-            let start = Instant::now();
             let final_context = AggAllContext::new(
                 &request.get_ref().base_dir,
                 request.get_ref().block_no,
@@ -295,28 +588,69 @@ impl ProverService for ProverServiceSVC {
                 request.get_ref().proof_num,
                 &request.get_ref().receipt_dir,
                 &request.get_ref().output_dir,
+                &request.get_ref().agg_receipt_path,
             );
 
-            let agg_all_func = move || {
+            let agg_all_func = move |cancelled| {
                 let s_ctx: AggAllContext = final_context;
-                Pipeline::new().prove_aggregate_all(&s_ctx)
+                Pipeline::with_cancel_flag(cancelled)
+                    .prove_aggregate_all(&s_ctx)
+                    .map(|done| (done, 0))
             };
-            let result = run_back_task(agg_all_func).await;
-            let mut response = prover_service::AggregateAllResponse {
+            let (result, _) = submit_job(
+                "aggregate_all",
+                TASK_ITYPE_AGGALL,
+                &request.get_ref().proof_id,
+                &request.get_ref().computed_request_id,
+                agg_all_func,
+            );
+            let response = prover_service::AggregateAllResponse {
                 proof_id: request.get_ref().proof_id.clone(),
                 computed_request_id: request.get_ref().computed_request_id.clone(),
-                ..Default::default()
+                result: Some(result),
             };
-            on_done!(result, response);
-            let end = Instant::now();
-            let elapsed = end.duration_since(start);
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    async fn wrap(
+        &self,
+        request: Request<WrapRequest>,
+    ) -> tonic::Result<Response<WrapResponse>, Status> {
+        metrics::record_metrics("prover::wrap", || async {
             log::info!(
-                "[aggregate_all] {}:{} code:{} elapsed:{} end",
+                "[wrap] {}:{} start",
                 request.get_ref().proof_id,
                 request.get_ref().computed_request_id,
-                response.result.as_ref().unwrap().code,
-                elapsed.as_secs()
             );
+            log::debug!("{:#?}", request);
+            let wrap_context = WrapContext::new(
+                &request.get_ref().base_dir,
+                request.get_ref().block_no,
+                request.get_ref().seg_size,
+                &request.get_ref().agg_receipt_path,
+                &request.get_ref().output_dir,
+            );
+
+            let wrap_func = move |cancelled| {
+                let wrap_ctx = wrap_context;
+                Pipeline::with_cancel_flag(cancelled)
+                    .prove_wrap(&wrap_ctx)
+                    .map(|done| (done, 0))
+            };
+            let (result, _) = submit_job(
+                "wrap",
+                TASK_ITYPE_WRAP,
+                &request.get_ref().proof_id,
+                &request.get_ref().computed_request_id,
+                wrap_func,
+            );
+            let response = prover_service::WrapResponse {
+                proof_id: request.get_ref().proof_id.clone(),
+                computed_request_id: request.get_ref().computed_request_id.clone(),
+                result: Some(result),
+            };
             Ok(Response::new(response))
         })
         .await
@@ -333,4 +667,128 @@ impl ProverService for ProverServiceSVC {
         })
         .await
     }
+
+    async fn cancel_task(
+        &self,
+        request: Request<CancelTaskRequest>,
+    ) -> tonic::Result<Response<CancelTaskResponse>, Status> {
+        metrics::record_metrics("prover::cancel_task", || async {
+            log::info!(
+                "[cancel_task] {}:{}",
+                request.get_ref().proof_id,
+                request.get_ref().computed_request_id,
+            );
+            let flag = CANCEL_FLAGS
+                .lock()
+                .unwrap()
+                .get(&request.get_ref().computed_request_id)
+                .cloned();
+            let result = match flag {
+                Some(flag) => {
+                    flag.store(true, Ordering::Relaxed);
+                    Result {
+                        code: ResultCode::Ok.into(),
+                        message: "SUCCESS".to_string(),
+                    }
+                }
+                None => Result {
+                    code: ResultCode::InvalidParameter.into(),
+                    message: "task not found".to_string(),
+                },
+            };
+            let response = prover_service::CancelTaskResponse {
+                proof_id: request.get_ref().proof_id.clone(),
+                computed_request_id: request.get_ref().computed_request_id.clone(),
+                result: Some(result),
+            };
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    async fn drain(
+        &self,
+        request: Request<DrainRequest>,
+    ) -> tonic::Result<Response<DrainResponse>, Status> {
+        metrics::record_metrics("prover::drain", || async {
+            let token = config::instance().lock().unwrap().node_token.clone();
+            // without a token only SIGTERM drains the node
+            let result = match token {
+                None => Result {
+                    code: ResultCode::InvalidParameter.into(),
+                    message: "drain is disabled without node_token".to_string(),
+                },
+                Some(token) if token != request.get_ref().token => Result {
+                    code: ResultCode::InvalidParameter.into(),
+                    message: "invalid token".to_string(),
+                },
+                Some(_) => {
+                    drain();
+                    Result {
+                        code: ResultCode::Ok.into(),
+                        message: "SUCCESS".to_string(),
+                    }
+                }
+            };
+            let response = prover_service::DrainResponse {
+                result: Some(result),
+                pending_jobs: pending_jobs() as u32,
+            };
+            Ok(Response::new(response))
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    async fn finished_state(computed_request_id: &str) -> TaskState {
+        loop {
+            if let Some(job) = JOBS.lock().unwrap().get(computed_request_id) {
+                if job.finish_ts != 0 {
+                    return job.state;
+                }
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resubmit_job() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let submit = |ok: bool| {
+            let runs = runs.clone();
+            submit_job("test", TASK_ITYPE_SPLIT, "proof", "resubmit", move |_| {
+                runs.fetch_add(1, Ordering::Relaxed);
+                if ok {
+                    Ok((true, 3))
+                } else {
+                    Err("failed".to_string())
+                }
+            })
+        };
+
+        let (result, _) = submit(false);
+        assert_eq!(result, pending_result(TaskState::Queued));
+        // still queued, it is not run twice
+        let (result, _) = submit(false);
+        assert_eq!(result, pending_result(TaskState::Queued));
+        assert_eq!(finished_state("resubmit").await, TaskState::Failed);
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+
+        // a failed job runs again
+        let (result, _) = submit(true);
+        assert_eq!(result, pending_result(TaskState::Queued));
+        assert_eq!(finished_state("resubmit").await, TaskState::Done);
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+
+        // a done one answers with its result
+        let (result, total_steps) = submit(true);
+        assert_eq!(result.code, ResultCode::Ok as i32);
+        assert_eq!(total_steps, 3);
+        assert_eq!(runs.load(Ordering::Relaxed), 2);
+    }
 }
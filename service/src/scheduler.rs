@@ -0,0 +1,514 @@
+use crate::prover_client::get_nodes;
+use crate::prover_client::prover_service::prover_service_client::ProverServiceClient;
+use crate::prover_client::prover_service::{get_status_response, GetStatusResponse, JobSlots};
+use crate::prover_node::{min_memory_class, ProverNode};
+use common::tls::Config as TlsConfig;
+use once_cell::sync::OnceCell;
+use stage::contexts::GenerateContext;
+use stage::tasks::{TASK_ITYPE_AGG, TASK_ITYPE_PROVE, TASK_ITYPE_SPLIT, TASK_ITYPE_WRAP};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+use tokio::time;
+use tonic::transport::Channel;
+
+// node addr, client, lease id
+type Grant = (String, ProverServiceClient<Channel>, u64);
+
+struct Waiter {
+    proof_id: String,
+    itype: i32,
+    seg_size: u32,
+    excluded_nodes: Vec<String>,
+    seq: u64,
+    tx: oneshot::Sender<Grant>,
+}
+
+struct Proof {
+    user: String,
+    priority: i32,
+    // 0 for no deadline
+    deadline_ts: u64,
+}
+
+/// Hands out the prover node slots to the tasks of all running proofs. A free slot goes to the
+/// waiting task of the proof with the highest priority, then the earliest deadline, then
+/// to the task whose user holds the smallest weighted share of the nodes, then to the proof
+/// of that user with the fewest running tasks, then first come first served.
+#[derive(Default)]
+pub struct Scheduler {
+    waiters: Vec<Waiter>,
+    proofs: HashMap<String, Proof>,
+    // lease id => (node addr, proof_id, task type), a node takes as many tasks of a type as
+    // it has slots for
+    leases: HashMap<u64, (String, String, i32)>,
+    seq: u64,
+}
+
+static INSTANCE: OnceCell<Mutex<Scheduler>> = OnceCell::new();
+
+fn instance() -> &'static Mutex<Scheduler> {
+    INSTANCE.get_or_init(|| Mutex::new(Scheduler::default()))
+}
+
+/// Released when the RPC holding the node slot is done
+pub struct Lease {
+    id: u64,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        instance().lock().unwrap().leases.remove(&self.id);
+    }
+}
+
+pub fn register(proof_id: &str, context: &GenerateContext) {
+    instance().lock().unwrap().proofs.insert(
+        proof_id.to_string(),
+        Proof {
+            user: context.user_address.clone(),
+            priority: context.priority,
+            deadline_ts: context.deadline_ts,
+        },
+    );
+}
+
+/// Forget a finished proof, its queued tasks get no node
+pub fn unregister(proof_id: &str) {
+    let mut scheduler = instance().lock().unwrap();
+    scheduler.proofs.remove(proof_id);
+    scheduler
+        .waiters
+        .retain(|waiter| waiter.proof_id != proof_id);
+}
+
+/// Queue for a slot on a prover node, None once the proof is unregistered
+pub async fn acquire(
+    proof_id: &str,
+    itype: i32,
+    seg_size: u32,
+    excluded_nodes: &[String],
+) -> Option<(String, ProverServiceClient<Channel>, Lease)> {
+    let (tx, rx) = oneshot::channel();
+    {
+        let mut scheduler = instance().lock().unwrap();
+        if !scheduler.proofs.contains_key(proof_id) {
+            return None;
+        }
+        scheduler.seq += 1;
+        let seq = scheduler.seq;
+        scheduler.waiters.push(Waiter {
+            proof_id: proof_id.to_string(),
+            itype,
+            seg_size,
+            excluded_nodes: excluded_nodes.to_vec(),
+            seq,
+            tx,
+        });
+    }
+    let (addr, client, id) = rx.await.ok()?;
+    Some((addr, client, Lease { id }))
+}
+
+fn slots_of(slots: &JobSlots, itype: i32) -> u32 {
+    match itype {
+        TASK_ITYPE_SPLIT => slots.split,
+        TASK_ITYPE_PROVE => slots.prove,
+        TASK_ITYPE_AGG => slots.agg,
+        TASK_ITYPE_WRAP => slots.wrap,
+        _ => slots.agg_all,
+    }
+}
+
+fn user_weight(address: &str) -> usize {
+    crate::config::instance()
+        .lock()
+        .unwrap()
+        .user_weights
+        .as_ref()
+        .and_then(|weights| weights.get(address).copied())
+        .unwrap_or(1)
+        .max(1) as usize
+}
+
+impl Scheduler {
+    /// Whether the node can take one more task of the type. A node that does not report
+    /// its slots takes a single task at a time, a booting node only takes splits and a
+    /// halted one none.
+    fn has_slot(&self, addr: &str, status: &GetStatusResponse, itype: i32) -> bool {
+        match get_status_response::Status::from_i32(status.status) {
+            // draining, it takes no new jobs
+            Some(get_status_response::Status::Halt) => return false,
+            Some(get_status_response::Status::Booting) if itype != TASK_ITYPE_SPLIT => {
+                return false
+            }
+            _ => {}
+        }
+        let leased = self
+            .leases
+            .values()
+            .filter(|(lease_addr, _, lease_itype)| {
+                lease_addr == addr && (status.total_slots.is_none() || *lease_itype == itype)
+            })
+            .count() as u32;
+        match (&status.total_slots, &status.free_slots) {
+            (Some(total), Some(free)) => {
+                slots_of(free, itype) > 0 && slots_of(total, itype) > leased
+            }
+            _ => {
+                let status = get_status_response::Status::from_i32(status.status);
+                leased == 0
+                    && (status == Some(get_status_response::Status::Idle)
+                        || status == Some(get_status_response::Status::Unspecified))
+            }
+        }
+    }
+
+    /// Index of the waiter a slot of the node goes to, only tasks the node declared it
+    /// takes are considered
+    fn next_waiter(
+        &self,
+        node: &ProverNode,
+        status: &GetStatusResponse,
+        nodes: &[String],
+    ) -> Option<usize> {
+        let addr = node.addr.as_str();
+        let mut user_running: HashMap<&str, usize> = HashMap::new();
+        let mut proof_running: HashMap<&str, usize> = HashMap::new();
+        for (_, proof_id, _) in self.leases.values() {
+            *proof_running.entry(proof_id).or_default() += 1;
+            if let Some(proof) = self.proofs.get(proof_id) {
+                *user_running.entry(&proof.user).or_default() += 1;
+            }
+        }
+        self.waiters
+            .iter()
+            .enumerate()
+            .filter(|(_, waiter)| {
+                node.capabilities.can_run(
+                    waiter.itype,
+                    waiter.seg_size,
+                    min_memory_class(waiter.itype),
+                ) && self.has_slot(addr, status, waiter.itype)
+            })
+            .filter(|(_, waiter)| {
+                // a node the task failed on is only used when there is no other node
+                !waiter.excluded_nodes.iter().any(|node| node == addr)
+                    || nodes
+                        .iter()
+                        .all(|node| waiter.excluded_nodes.contains(node))
+            })
+            .min_by_key(|(_, waiter)| {
+                let (user, priority, deadline_ts) = match self.proofs.get(&waiter.proof_id) {
+                    Some(proof) => (proof.user.as_str(), proof.priority, proof.deadline_ts),
+                    None => ("", 0, 0),
+                };
+                let deadline_ts = if deadline_ts == 0 {
+                    u64::MAX
+                } else {
+                    deadline_ts
+                };
+                let running = user_running.get(user).copied().unwrap_or(0);
+                // weighted share, scaled so that small counts still order correctly
+                let share = running * 1000 / user_weight(user);
+                let proof = proof_running
+                    .get(waiter.proof_id.as_str())
+                    .copied()
+                    .unwrap_or(0);
+                (Reverse(priority), deadline_ts, share, proof, waiter.seq)
+            })
+            .map(|(index, _)| index)
+    }
+
+    fn spare_node(
+        &self,
+        nodes: &[ProverNode],
+        itype: i32,
+        seg_size: u32,
+        excluded_nodes: &[String],
+    ) -> bool {
+        nodes
+            .iter()
+            .filter(|node| !excluded_nodes.contains(&node.addr))
+            .filter(|node| {
+                node.capabilities
+                    .can_run(itype, seg_size, min_memory_class(itype))
+            })
+            .any(|node| match node.get_info().status {
+                Some(status) => self.has_slot(&node.addr, &status, itype),
+                None => false,
+            })
+    }
+}
+
+/// Whether a node outside of `excluded_nodes` that takes the task had a free slot for it at
+/// its last status. A speculative copy is only started then, on a single node cluster it
+/// would queue behind the task it copies.
+pub fn has_spare_node(itype: i32, seg_size: u32, excluded_nodes: &[String]) -> bool {
+    let nodes = get_nodes();
+    instance()
+        .lock()
+        .unwrap()
+        .spare_node(&nodes, itype, seg_size, excluded_nodes)
+}
+
+/// Lease one free node slot to a waiting task, false when there was nothing to do
+async fn dispatch(tls_config: Option<TlsConfig>) -> bool {
+    let nodes = get_nodes();
+    let addrs: Vec<String> = nodes.iter().map(|node| node.addr.clone()).collect();
+    for mut node in nodes {
+        if instance().lock().unwrap().waiters.is_empty() {
+            return false;
+        }
+        if let Some((client, status)) = node.get_status(tls_config.clone()).await {
+            let mut scheduler = instance().lock().unwrap();
+            while let Some(index) = scheduler.next_waiter(&node, &status, &addrs) {
+                let waiter = scheduler.waiters.remove(index);
+                scheduler.seq += 1;
+                let id = scheduler.seq;
+                if waiter
+                    .tx
+                    .send((node.addr.clone(), client.clone(), id))
+                    .is_ok()
+                {
+                    scheduler.leases.insert(
+                        id,
+                        (node.addr.clone(), waiter.proof_id.clone(), waiter.itype),
+                    );
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+pub async fn start(tls_config: Option<TlsConfig>) {
+    tokio::spawn(async move {
+        loop {
+            if !dispatch(tls_config.clone()).await {
+                time::sleep(time::Duration::from_secs(1)).await;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use get_status_response::Status;
+
+    const ADDR: &str = "127.0.0.1:50000";
+
+    fn status(status: Status, slots: Option<JobSlots>) -> GetStatusResponse {
+        GetStatusResponse {
+            status: status.into(),
+            total_slots: slots.clone(),
+            free_slots: slots,
+            ..Default::default()
+        }
+    }
+
+    fn add_proof(
+        scheduler: &mut Scheduler,
+        proof_id: &str,
+        user: &str,
+        priority: i32,
+        deadline_ts: u64,
+    ) {
+        scheduler.proofs.insert(
+            proof_id.to_string(),
+            Proof {
+                user: user.to_string(),
+                priority,
+                deadline_ts,
+            },
+        );
+    }
+
+    fn add_waiter(scheduler: &mut Scheduler, proof_id: &str, excluded_nodes: &[&str]) {
+        scheduler.seq += 1;
+        let (tx, _) = oneshot::channel();
+        scheduler.waiters.push(Waiter {
+            proof_id: proof_id.to_string(),
+            itype: TASK_ITYPE_PROVE,
+            seg_size: 0,
+            excluded_nodes: excluded_nodes.iter().map(|node| node.to_string()).collect(),
+            seq: scheduler.seq,
+            tx,
+        });
+    }
+
+    fn add_lease(scheduler: &mut Scheduler, addr: &str, proof_id: &str, itype: i32) {
+        scheduler.seq += 1;
+        scheduler.leases.insert(
+            scheduler.seq,
+            (addr.to_string(), proof_id.to_string(), itype),
+        );
+    }
+
+    // proof id of the waiter the next slot of the node goes to
+    fn next(scheduler: &Scheduler, nodes: &[String]) -> Option<String> {
+        let node = ProverNode::new(&ADDR.to_string());
+        let slots = JobSlots {
+            prove: 1,
+            ..Default::default()
+        };
+        scheduler
+            .next_waiter(&node, &status(Status::Idle, Some(slots)), nodes)
+            .map(|index| scheduler.waiters[index].proof_id.clone())
+    }
+
+    fn take(scheduler: &mut Scheduler, nodes: &[String]) -> Option<String> {
+        let proof_id = next(scheduler, nodes)?;
+        let index = scheduler
+            .waiters
+            .iter()
+            .position(|waiter| waiter.proof_id == proof_id)
+            .unwrap();
+        scheduler.waiters.remove(index);
+        Some(proof_id)
+    }
+
+    #[test]
+    fn test_priority_and_deadline() {
+        let nodes = vec![ADDR.to_string()];
+        let mut scheduler = Scheduler::default();
+        add_proof(&mut scheduler, "none", "a", 0, 0);
+        add_proof(&mut scheduler, "late", "b", 0, 2000);
+        add_proof(&mut scheduler, "early", "c", 0, 1000);
+        add_proof(&mut scheduler, "urgent", "d", 1, 0);
+        for proof_id in ["none", "late", "early", "urgent"] {
+            add_waiter(&mut scheduler, proof_id, &[]);
+        }
+        // priority first, then the earliest deadline, a proof without one goes last
+        for proof_id in ["urgent", "early", "late", "none"] {
+            assert_eq!(take(&mut scheduler, &nodes).as_deref(), Some(proof_id));
+        }
+        assert_eq!(take(&mut scheduler, &nodes), None);
+    }
+
+    #[test]
+    fn test_fairness() {
+        let nodes = vec![ADDR.to_string()];
+        let mut scheduler = Scheduler::default();
+        add_proof(&mut scheduler, "a1", "alice", 0, 0);
+        add_proof(&mut scheduler, "a2", "alice", 0, 0);
+        add_proof(&mut scheduler, "b1", "bob", 0, 0);
+        // alice already runs two tasks elsewhere, bob one
+        add_lease(&mut scheduler, "127.0.0.1:50001", "a1", TASK_ITYPE_PROVE);
+        add_lease(&mut scheduler, "127.0.0.1:50002", "a1", TASK_ITYPE_PROVE);
+        add_lease(&mut scheduler, "127.0.0.1:50003", "b1", TASK_ITYPE_PROVE);
+        add_waiter(&mut scheduler, "a1", &[]);
+        add_waiter(&mut scheduler, "a2", &[]);
+        add_waiter(&mut scheduler, "b1", &[]);
+        // the user with the smaller share goes first
+        assert_eq!(take(&mut scheduler, &nodes).as_deref(), Some("b1"));
+        // then the proof of the user with the fewest running tasks
+        assert_eq!(take(&mut scheduler, &nodes).as_deref(), Some("a2"));
+        assert_eq!(take(&mut scheduler, &nodes).as_deref(), Some("a1"));
+
+        // first come first served when all else is equal
+        add_waiter(&mut scheduler, "a2", &[]);
+        add_waiter(&mut scheduler, "a2", &[]);
+        let first = scheduler.waiters[0].seq;
+        let node = ProverNode::new(&ADDR.to_string());
+        let slots = JobSlots {
+            prove: 1,
+            ..Default::default()
+        };
+        let index = scheduler
+            .next_waiter(&node, &status(Status::Idle, Some(slots)), &nodes)
+            .unwrap();
+        assert_eq!(scheduler.waiters[index].seq, first);
+    }
+
+    #[test]
+    fn test_excluded_nodes() {
+        let mut scheduler = Scheduler::default();
+        add_proof(&mut scheduler, "p", "a", 0, 0);
+        add_waiter(&mut scheduler, "p", &[ADDR]);
+        // another node can take it
+        let nodes = vec![ADDR.to_string(), "127.0.0.1:50001".to_string()];
+        assert_eq!(next(&scheduler, &nodes), None);
+        // the failed node is the only one left
+        let nodes = vec![ADDR.to_string()];
+        assert_eq!(next(&scheduler, &nodes).as_deref(), Some("p"));
+    }
+
+    #[test]
+    fn test_spare_node() {
+        let scheduler = Scheduler::default();
+        let node = ProverNode::new(&ADDR.to_string());
+        let excluded = vec![ADDR.to_string()];
+        // no status yet
+        assert!(!scheduler.spare_node(&[node.clone()], TASK_ITYPE_PROVE, 0, &[]));
+        let slots = JobSlots {
+            prove: 1,
+            ..Default::default()
+        };
+        node.info.lock().unwrap().status = Some(status(Status::Idle, Some(slots)));
+        assert!(scheduler.spare_node(&[node.clone()], TASK_ITYPE_PROVE, 0, &[]));
+        // the only node is the one the task runs on
+        assert!(!scheduler.spare_node(&[node.clone()], TASK_ITYPE_PROVE, 0, &excluded));
+        assert!(!scheduler.spare_node(&[node], TASK_ITYPE_AGG, 0, &[]));
+    }
+
+    #[test]
+    fn test_has_slot() {
+        let mut scheduler = Scheduler::default();
+        let slots = JobSlots {
+            split: 1,
+            prove: 2,
+            ..Default::default()
+        };
+        assert!(!scheduler.has_slot(
+            ADDR,
+            &status(Status::Halt, Some(slots.clone())),
+            TASK_ITYPE_SPLIT
+        ));
+        assert!(scheduler.has_slot(
+            ADDR,
+            &status(Status::Booting, Some(slots.clone())),
+            TASK_ITYPE_SPLIT
+        ));
+        assert!(!scheduler.has_slot(
+            ADDR,
+            &status(Status::Booting, Some(slots.clone())),
+            TASK_ITYPE_PROVE
+        ));
+
+        // a node that reports its slots takes as many tasks of a type
+        let computing = status(Status::Computing, Some(slots));
+        add_lease(&mut scheduler, ADDR, "p", TASK_ITYPE_PROVE);
+        assert!(scheduler.has_slot(ADDR, &computing, TASK_ITYPE_PROVE));
+        add_lease(&mut scheduler, ADDR, "p", TASK_ITYPE_PROVE);
+        assert!(!scheduler.has_slot(ADDR, &computing, TASK_ITYPE_PROVE));
+        assert!(scheduler.has_slot(ADDR, &computing, TASK_ITYPE_SPLIT));
+        assert!(!scheduler.has_slot(ADDR, &computing, TASK_ITYPE_AGG));
+        // leases on other nodes do not count
+        assert!(scheduler.has_slot("127.0.0.1:50001", &computing, TASK_ITYPE_PROVE));
+
+        // one that does not takes a single task at a time
+        let mut scheduler = Scheduler::default();
+        assert!(scheduler.has_slot(ADDR, &status(Status::Idle, None), TASK_ITYPE_PROVE));
+        assert!(!scheduler.has_slot(ADDR, &status(Status::Computing, None), TASK_ITYPE_PROVE));
+        add_lease(&mut scheduler, ADDR, "p", TASK_ITYPE_SPLIT);
+        assert!(!scheduler.has_slot(ADDR, &status(Status::Idle, None), TASK_ITYPE_PROVE));
+    }
+
+    #[test]
+    fn test_lease_drop() {
+        let id = u64::MAX;
+        instance()
+            .lock()
+            .unwrap()
+            .leases
+            .insert(id, (ADDR.to_string(), "p".to_string(), TASK_ITYPE_PROVE));
+        let lease = Lease { id };
+        assert!(instance().lock().unwrap().leases.contains_key(&id));
+        drop(lease);
+        assert!(!instance().lock().unwrap().leases.contains_key(&id));
+    }
+}
@@ -0,0 +1,58 @@
+use crate::stage_service::stage_service::WatchProofResponse;
+use lazy_static::lazy_static;
+use stage::stage::Stage;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+lazy_static! {
+    static ref WATCHERS: Mutex<HashMap<String, broadcast::Sender<WatchProofResponse>>> =
+        Mutex::new(HashMap::new());
+}
+
+pub fn new_event(stage: &Stage, status: i32) -> WatchProofResponse {
+    let (prove_tasks_done, prove_tasks_total) = stage.prove_progress();
+    let (agg_tasks_done, agg_tasks_total) = stage.agg_progress();
+    WatchProofResponse {
+        proof_id: stage.generate_context.proof_id.clone(),
+        status: status as u32,
+        step: stage.step.clone().into(),
+        prove_tasks_done,
+        prove_tasks_total,
+        agg_tasks_done,
+        agg_tasks_total,
+        ..Default::default()
+    }
+}
+
+pub fn subscribe(proof_id: &str) -> broadcast::Receiver<WatchProofResponse> {
+    WATCHERS
+        .lock()
+        .unwrap()
+        .entry(proof_id.to_string())
+        .or_insert_with(|| broadcast::channel(128).0)
+        .subscribe()
+}
+
+/// Drop the channel once the last watcher of a proof is gone
+pub fn release(proof_id: &str) {
+    let mut watchers = WATCHERS.lock().unwrap();
+    if let Some(sender) = watchers.get(proof_id) {
+        if sender.receiver_count() == 0 {
+            watchers.remove(proof_id);
+        }
+    }
+}
+
+pub fn publish(event: WatchProofResponse) {
+    if let Some(sender) = WATCHERS.lock().unwrap().get(&event.proof_id) {
+        let _ = sender.send(event);
+    }
+}
+
+/// Send the last event of a proof and close all of its streams
+pub fn close(event: WatchProofResponse) {
+    if let Some(sender) = WATCHERS.lock().unwrap().remove(&event.proof_id) {
+        let _ = sender.send(event);
+    }
+}
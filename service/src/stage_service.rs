@@ -1,10 +1,17 @@
 use anyhow::Error;
 use common::tls::Config as TlsConfig;
 use stage_service::stage_service_server::StageService;
+use stage_service::{CancelProofRequest, CancelProofResponse};
 use stage_service::{GenerateProofRequest, GenerateProofResponse};
 use stage_service::{GetStatusRequest, GetStatusResponse};
+use stage_service::{HeartbeatRequest, HeartbeatResponse};
+use stage_service::{RegisterNodeRequest, RegisterNodeResponse};
+use stage_service::{RetryProofRequest, RetryProofResponse};
+use stage_service::{WatchProofRequest, WatchProofResponse};
 use std::sync::Mutex;
 
+use tokio::sync::broadcast::error::RecvError;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
 use crate::config;
@@ -17,7 +24,10 @@ use std::str::FromStr;
 
 use crate::database;
 use crate::metrics;
+use crate::prover_node;
+use crate::stage_events;
 use crate::stage_worker;
+use stage::stage::Step;
 
 #[allow(clippy::module_inception)]
 pub mod stage_service {
@@ -62,6 +72,14 @@ impl StageServiceSVC {
         })
     }
 
+    /// Nodes can only register when the stage has a node_token, and must present it
+    fn valid_node_token(token: &str) -> bool {
+        match &config::instance().lock().unwrap().node_token {
+            Some(node_token) => !node_token.is_empty() && node_token == token,
+            None => false,
+        }
+    }
+
     pub fn valid_signature(&self, request: &GenerateProofRequest) -> Result<String, Error> {
         let sign_data = match request.block_no {
             Some(block_no) => {
@@ -71,14 +89,170 @@ impl StageServiceSVC {
                 format!("{}&{}", request.proof_id, request.seg_size)
             }
         };
-        let signature = Signature::from_str(&request.signature)?;
+        Self::recover_address(&sign_data, &request.signature)
+    }
+
+    fn recover_address(sign_data: &str, signature: &str) -> Result<String, Error> {
+        let signature = Signature::from_str(signature)?;
         let recovered = signature.recover(sign_data)?;
         Ok(hex::encode(recovered))
     }
 }
 
+// in the order a proof goes through the steps
+const STEP_TASK_TYPES: [(Step, i32); 6] = [
+    (Step::InSplit, stage::tasks::TASK_ITYPE_SPLIT),
+    (Step::InProve, stage::tasks::TASK_ITYPE_PROVE),
+    (Step::InAgg, stage::tasks::TASK_ITYPE_AGG),
+    (Step::InAggAll, stage::tasks::TASK_ITYPE_AGGALL),
+    (Step::InWrap, stage::tasks::TASK_ITYPE_WRAP),
+    (Step::InFinal, stage::tasks::TASK_ITYPE_FINAL),
+];
+
+fn task_span(task: &database::ProveTask) -> (u64, u64) {
+    let content: serde_json::Value = task
+        .content
+        .as_deref()
+        .and_then(|content| serde_json::from_str(content).ok())
+        .unwrap_or_default();
+    (
+        content["start_ts"].as_u64().unwrap_or(0),
+        content["finish_ts"].as_u64().unwrap_or(0),
+    )
+}
+
+/// Fill the task counters, per step elapsed time and ETA from the prove_task rows.
+/// The ETA spreads the remaining tasks over all prover nodes and only accounts for
+/// task types that already have a finished task to average over.
+fn fill_progress(
+    response: &mut GetStatusResponse,
+    task: &database::StageTask,
+    prove_tasks: &[database::ProveTask],
+) {
+    let success = stage::tasks::TASK_STATE_SUCCESS as i32;
+    let computing = task.status == stage_service::Status::Computing as i32;
+    let now = stage_worker::now_timestamp();
+    let finished = |itypes: &[i32]| {
+        prove_tasks
+            .iter()
+            .filter(|row| itypes.contains(&row.itype) && row.status == success)
+            .collect::<Vec<_>>()
+    };
+
+    let prove_done = finished(&[stage::tasks::TASK_ITYPE_PROVE]);
+    let agg_done = finished(&[
+        stage::tasks::TASK_ITYPE_AGG,
+        stage::tasks::TASK_ITYPE_AGGALL,
+    ]);
+    response.prove_tasks_done = prove_done.len() as u32;
+    response.prove_tasks_total = (task.prove_total as u32).max(response.prove_tasks_done);
+    response.agg_tasks_done = agg_done.len() as u32;
+    response.agg_tasks_total = (task.agg_total as u32).max(response.agg_tasks_done);
+
+    // split, prove and agg overlap, so each step is timed by its own tasks. Only a step
+    // without a finished task yet is assumed to start when the steps before it ended.
+    let mut prev_end = 0;
+    for (step, itype) in STEP_TASK_TYPES {
+        let step: i32 = step.into();
+        let spans: Vec<(u64, u64)> = prove_tasks
+            .iter()
+            .filter(|row| row.itype == itype)
+            .map(task_span)
+            .collect();
+        let unfinished = match itype {
+            stage::tasks::TASK_ITYPE_PROVE => {
+                response.prove_tasks_done < response.prove_tasks_total
+            }
+            stage::tasks::TASK_ITYPE_AGG => response.agg_tasks_done < response.agg_tasks_total,
+            _ => false,
+        };
+        let is_running = computing && (task.step == step || (unfinished && !spans.is_empty()));
+        if spans.is_empty() && !is_running {
+            continue;
+        }
+        let start = spans
+            .iter()
+            .map(|span| span.0)
+            .filter(|ts| *ts > 0)
+            .min()
+            .unwrap_or(if prev_end > 0 { prev_end } else { now });
+        let end = if is_running {
+            now
+        } else {
+            spans.iter().map(|span| span.1).max().unwrap_or(start)
+        };
+        response.step_elapsed.push(stage_service::StepElapsed {
+            step,
+            elapsed_secs: end.saturating_sub(start),
+        });
+        prev_end = prev_end.max(end);
+    }
+
+    if computing {
+        let nodes = crate::prover_node::instance()
+            .lock()
+            .unwrap()
+            .get_nodes()
+            .len()
+            .max(1) as u64;
+        let average = |rows: &[&database::ProveTask]| {
+            if rows.is_empty() {
+                0
+            } else {
+                rows.iter().map(|row| row.time_cost as u64).sum::<u64>() / rows.len() as u64
+            }
+        };
+        let remaining = |total: u32, done: u32| (total.saturating_sub(done) as u64).div_ceil(nodes);
+        response.eta_secs = remaining(response.prove_tasks_total, response.prove_tasks_done)
+            * average(&prove_done)
+            + remaining(response.agg_tasks_total, response.agg_tasks_done) * average(&agg_done);
+    }
+}
+
+/// First event of watch_proof, with the same task counters get_status reports
+async fn watch_snapshot(db: &database::Database, task: database::StageTask) -> WatchProofResponse {
+    let prove_tasks = db.get_prove_tasks(&task.id).await.unwrap_or_default();
+    let mut progress = GetStatusResponse::default();
+    fill_progress(&mut progress, &task, &prove_tasks);
+    WatchProofResponse {
+        proof_id: task.id,
+        status: task.status as u32,
+        step: task.step,
+        prove_tasks_done: progress.prove_tasks_done,
+        prove_tasks_total: progress.prove_tasks_total,
+        agg_tasks_done: progress.agg_tasks_done,
+        agg_tasks_total: progress.agg_tasks_total,
+        ..Default::default()
+    }
+}
+
+/// A proof misses its deadline when it is still computing past it, or its last task
+/// finished after it.
+fn fill_deadline(
+    response: &mut GetStatusResponse,
+    task: &database::StageTask,
+    prove_tasks: &[database::ProveTask],
+) {
+    if task.deadline_ts <= 0 {
+        return;
+    }
+    response.deadline_ts = task.deadline_ts as u64;
+    let finish_ts = if task.status == stage_service::Status::Computing as i32 {
+        stage_worker::now_timestamp()
+    } else {
+        prove_tasks
+            .iter()
+            .map(|row| task_span(row).1)
+            .max()
+            .unwrap_or(0)
+    };
+    response.deadline_missed = finish_ts > response.deadline_ts;
+}
+
 #[tonic::async_trait]
 impl StageService for StageServiceSVC {
+    type WatchProofStream = ReceiverStream<tonic::Result<WatchProofResponse, Status>>;
+
     async fn get_status(
         &self,
         request: Request<GetStatusRequest>,
@@ -92,6 +266,7 @@ impl StageService for StageServiceSVC {
             if let Ok(task) = task {
                 response.status = task.status as u32;
                 response.step = task.step;
+                response.error_message = task.error_message.clone().unwrap_or_default();
                 let execute_info: Vec<stage::tasks::SplitTask> = self
                     .db
                     .get_prove_task_infos(
@@ -103,6 +278,13 @@ impl StageService for StageServiceSVC {
                 if !execute_info.is_empty() {
                     response.total_steps = execute_info[0].total_steps;
                 }
+                let prove_tasks = self
+                    .db
+                    .get_prove_tasks(&request.get_ref().proof_id)
+                    .await
+                    .unwrap_or_default();
+                fill_progress(&mut response, &task, &prove_tasks);
+                fill_deadline(&mut response, &task, &prove_tasks);
 
                 let (execute_only, precompile) = if let Some(context) = task.context {
                     match serde_json::from_str::<stage::contexts::GenerateContext>(&context) {
@@ -337,7 +519,7 @@ impl StageService for StageServiceSVC {
                 .map_err(|e| Status::internal(e.to_string()))?;
             let final_path = format!("{}/proof_with_public_inputs.json", final_dir);
 
-            let generate_context = stage::contexts::GenerateContext::new(
+            let mut generate_context = stage::contexts::GenerateContext::new(
                 &request.get_ref().proof_id,
                 &dir_path,
                 &elf_path,
@@ -355,6 +537,25 @@ impl StageService for StageServiceSVC {
                 &receipt_inputs_path,
                 &receipts_path,
             );
+            generate_context.user_address.clone_from(&user_address);
+            generate_context.priority = request.get_ref().priority;
+            generate_context.deadline_ts = request.get_ref().deadline_ts;
+            if let Some(agg_strategy) = &request.get_ref().agg_strategy {
+                generate_context.agg_strategy = stage::stage::AggStrategy {
+                    agg_all_max_proofs: agg_strategy.agg_all_max_proofs as usize,
+                    shape: match stage_service::AggTreeShape::from_i32(agg_strategy.shape) {
+                        Some(stage_service::AggTreeShape::LeftDeep) => {
+                            stage::stage::AggTreeShape::LeftDeep
+                        }
+                        _ => stage::stage::AggTreeShape::Balanced,
+                    },
+                    max_width: agg_strategy.max_width as usize,
+                };
+            } else if let Some(agg_strategy) =
+                config::instance().lock().unwrap().agg_strategy.clone()
+            {
+                generate_context.agg_strategy = agg_strategy;
+            }
 
             let _ = self
                 .db
@@ -363,6 +564,8 @@ impl StageService for StageServiceSVC {
                     &user_address,
                     stage_service::Status::Computing as i32,
                     &serde_json::to_string(&generate_context).unwrap(),
+                    generate_context.priority,
+                    generate_context.deadline_ts as i64,
                 )
                 .await;
             let mut proof_url = match &self.fileserver_url {
@@ -413,4 +616,245 @@ impl StageService for StageServiceSVC {
         })
         .await
     }
+
+    async fn cancel_proof(
+        &self,
+        request: Request<CancelProofRequest>,
+    ) -> tonic::Result<Response<CancelProofResponse>, Status> {
+        metrics::record_metrics("stage::cancel_proof", || async {
+            log::info!("[cancel_proof] {} start", request.get_ref().proof_id);
+            let mut response = stage_service::CancelProofResponse {
+                proof_id: request.get_ref().proof_id.clone(),
+                status: stage_service::Status::InvalidParameter as u32,
+                ..Default::default()
+            };
+            let address = match Self::recover_address(
+                &request.get_ref().proof_id,
+                &request.get_ref().signature,
+            ) {
+                Ok(address) => address,
+                Err(e) => {
+                    log::warn!(
+                        "[cancel_proof] {} invalid signature {:?}",
+                        request.get_ref().proof_id,
+                        e,
+                    );
+                    response.error_message = "invalid signature".to_string();
+                    return Ok(Response::new(response));
+                }
+            };
+            let rows_affected = self
+                .db
+                .cancel_stage_task(
+                    &request.get_ref().proof_id,
+                    &address,
+                    stage_service::Status::Cancelled as i32,
+                    stage_service::Status::Computing as i32,
+                )
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            if rows_affected == 1 {
+                response.status = stage_service::Status::Cancelled as u32;
+                // stops the worker of this instance at once, others see it on their next poll
+                if let Ok(task) = self.db.get_stage_task(&request.get_ref().proof_id).await {
+                    stage_events::publish(watch_snapshot(&self.db, task).await);
+                }
+            } else {
+                response.error_message =
+                    "proof not found, not computing or permission denied".to_string();
+            }
+            log::info!(
+                "[cancel_proof] {} status:{} end",
+                request.get_ref().proof_id,
+                response.status
+            );
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    async fn retry_proof(
+        &self,
+        request: Request<RetryProofRequest>,
+    ) -> tonic::Result<Response<RetryProofResponse>, Status> {
+        metrics::record_metrics("stage::retry_proof", || async {
+            log::info!("[retry_proof] {} start", request.get_ref().proof_id);
+            let mut response = stage_service::RetryProofResponse {
+                proof_id: request.get_ref().proof_id.clone(),
+                status: stage_service::Status::InvalidParameter as u32,
+                ..Default::default()
+            };
+            let address = match Self::recover_address(
+                &request.get_ref().proof_id,
+                &request.get_ref().signature,
+            ) {
+                Ok(address) => address,
+                Err(e) => {
+                    log::warn!(
+                        "[retry_proof] {} invalid signature {:?}",
+                        request.get_ref().proof_id,
+                        e,
+                    );
+                    response.error_message = "invalid signature".to_string();
+                    return Ok(Response::new(response));
+                }
+            };
+            let rows_affected = self
+                .db
+                .retry_stage_task(
+                    &request.get_ref().proof_id,
+                    &address,
+                    stage_service::Status::Computing as i32,
+                    &[
+                        stage_service::Status::SplitError as i32,
+                        stage_service::Status::ProveError as i32,
+                        stage_service::Status::AggError as i32,
+                        stage_service::Status::WrapError as i32,
+                        stage_service::Status::FinalError as i32,
+                    ],
+                )
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            if rows_affected == 1 {
+                response.status = stage_service::Status::Computing as u32;
+            } else {
+                response.error_message =
+                    "proof not found, not failed or permission denied".to_string();
+            }
+            log::info!(
+                "[retry_proof] {} status:{} end",
+                request.get_ref().proof_id,
+                response.status
+            );
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    async fn register_node(
+        &self,
+        request: Request<RegisterNodeRequest>,
+    ) -> tonic::Result<Response<RegisterNodeResponse>, Status> {
+        metrics::record_metrics("stage::register_node", || async {
+            let mut response = stage_service::RegisterNodeResponse {
+                status: stage_service::Status::InvalidParameter as u32,
+                heartbeat_interval_secs: prover_node::HEARTBEAT_INTERVAL_SECS,
+                ..Default::default()
+            };
+            if !Self::valid_node_token(&request.get_ref().token) {
+                log::warn!("[register_node] {} invalid token", request.get_ref().addr);
+                response.error_message = "invalid token".to_string();
+                return Ok(Response::new(response));
+            }
+            if request.get_ref().addr.is_empty() {
+                response.error_message = "invalid addr".to_string();
+                return Ok(Response::new(response));
+            }
+            let memory_class =
+                match prover_node::MemoryClass::from_name(&request.get_ref().memory_class) {
+                    Some(memory_class) => memory_class,
+                    None => {
+                        response.error_message = "invalid memory_class".to_string();
+                        return Ok(Response::new(response));
+                    }
+                };
+            let capabilities = prover_node::NodeCapabilities {
+                roles: request.get_ref().roles.clone(),
+                max_seg_size: request.get_ref().max_seg_size,
+                memory_class,
+            };
+            let added = prover_node::instance().lock().unwrap().register_node(
+                &request.get_ref().addr,
+                request.get_ref().snark,
+                capabilities,
+            );
+            log::info!(
+                "[register_node] {} snark:{} added:{}",
+                request.get_ref().addr,
+                request.get_ref().snark,
+                added
+            );
+            response.status = stage_service::Status::Success as u32;
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> tonic::Result<Response<HeartbeatResponse>, Status> {
+        metrics::record_metrics("stage::heartbeat", || async {
+            let mut response = stage_service::HeartbeatResponse {
+                status: stage_service::Status::InvalidParameter as u32,
+                ..Default::default()
+            };
+            if !Self::valid_node_token(&request.get_ref().token) {
+                response.error_message = "invalid token".to_string();
+            } else if prover_node::instance()
+                .lock()
+                .unwrap()
+                .heartbeat(&request.get_ref().addr)
+            {
+                response.status = stage_service::Status::Success as u32;
+            } else {
+                response.error_message = "node not registered".to_string();
+            }
+            Ok(Response::new(response))
+        })
+        .await
+    }
+
+    async fn watch_proof(
+        &self,
+        request: Request<WatchProofRequest>,
+    ) -> tonic::Result<Response<Self::WatchProofStream>, Status> {
+        metrics::record_metrics("stage::watch_proof", || async {
+            let proof_id = request.get_ref().proof_id.clone();
+            let task = self
+                .db
+                .get_stage_task(&proof_id)
+                .await
+                .map_err(|_| Status::not_found(format!("proof {} not found", proof_id)))?;
+            // subscribe before sending the snapshot so no event is lost in between
+            let mut events = stage_events::subscribe(&proof_id);
+            let (tx, rx) = tokio::sync::mpsc::channel(128);
+            let db = self.db.clone();
+            tokio::spawn(async move {
+                let computing = stage_service::Status::Computing as i32;
+                let mut status = task.status;
+                let mut closed = tx.send(Ok(watch_snapshot(&db, task).await)).await.is_err();
+                while !closed && status == computing {
+                    tokio::select! {
+                        event = events.recv() => match event {
+                            Ok(event) => {
+                                status = event.status as i32;
+                                closed = tx.send(Ok(event)).await.is_err();
+                            }
+                            Err(RecvError::Lagged(_)) => {}
+                            Err(RecvError::Closed) => {
+                                if let Ok(task) = db.get_stage_task(&proof_id).await {
+                                    let _ = tx.send(Ok(watch_snapshot(&db, task).await)).await;
+                                }
+                                break;
+                            }
+                        },
+                        // the proof may be running on another stage instance
+                        () = tokio::time::sleep(tokio::time::Duration::from_secs(10)) => {
+                            if let Ok(task) = db.get_stage_task(&proof_id).await {
+                                if task.status != computing {
+                                    status = task.status;
+                                    closed = tx.send(Ok(watch_snapshot(&db, task).await)).await.is_err();
+                                }
+                            }
+                        }
+                    }
+                }
+                drop(events);
+                stage_events::release(&proof_id);
+            });
+            Ok(Response::new(ReceiverStream::new(rx)))
+        })
+        .await
+    }
 }
@@ -1,18 +1,23 @@
 use crate::database;
 use crate::database::StageTask;
 use crate::prover_client;
+use crate::scheduler;
+use crate::stage_events;
 use crate::TlsConfig;
 use common::file;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::time;
 
 use stage::tasks::{
     Task, TASK_ITYPE_AGG, TASK_ITYPE_AGGALL, TASK_ITYPE_FINAL, TASK_ITYPE_PROVE, TASK_ITYPE_SPLIT,
+    TASK_ITYPE_WRAP,
 };
 
-use stage::stage::Step;
+use stage::stage::{Stage, Step};
+use stage::tasks::{AggTask, ProveTask};
 
 use stage::tasks::{TASK_STATE_FAILED, TASK_STATE_SUCCESS};
 
@@ -35,6 +40,83 @@ macro_rules! save_task {
     };
 }
 
+fn notify_task(stage: &Stage, itype: i32, task_id: &str, state: u32) {
+    if state == TASK_STATE_FAILED || state == TASK_STATE_SUCCESS {
+        let mut event = stage_events::new_event(
+            stage,
+            crate::stage_service::stage_service::Status::Computing.into(),
+        );
+        event.task_type = itype;
+        event.task_id = task_id.to_string();
+        event.task_state = state;
+        stage_events::publish(event);
+    }
+}
+
+/// Seconds between two checkpoints of the finished tasks of a step, a restarted stage
+/// service proves again at most what finished in the last interval
+const CHECKPOINT_INTERVAL: u64 = 5;
+
+/// Restore the stage from its last checkpoint, so that a restarted stage service only
+/// redispatches the tasks that had not finished yet.
+async fn load_stage(
+    db: &database::Database,
+    proof_id: &str,
+    generate_context: &stage::contexts::GenerateContext,
+) -> Stage {
+    if let Ok(Some(checkpoint)) = db.get_stage_task_checkpoint(proof_id).await {
+        match serde_json::from_str::<Stage>(&checkpoint) {
+            Ok(mut stage) => {
+                stage.resume();
+                log::info!("[stage] {} resume from checkpoint", proof_id);
+                return stage;
+            }
+            Err(e) => {
+                log::warn!("[stage] {} invalid checkpoint {:?}", proof_id, e);
+            }
+        }
+    }
+    Stage::new(generate_context.clone())
+}
+
+async fn save_checkpoint(db: &database::Database, proof_id: &str, stage: &Stage) {
+    match serde_json::to_string(stage) {
+        Ok(checkpoint) => {
+            if let Err(e) = db.update_stage_task_checkpoint(proof_id, &checkpoint).await {
+                log::warn!("[stage] {} save checkpoint failed {:?}", proof_id, e);
+            }
+        }
+        Err(e) => log::warn!("[stage] {} serialize checkpoint failed {:?}", proof_id, e),
+    }
+}
+
+/// Stop the speculative copy of a finished task, its result would be ignored anyway
+fn cancel_copies(proof_id: &str, task_id: &str) {
+    let proof_id = proof_id.to_string();
+    let task_id = task_id.to_string();
+    tokio::spawn(async move {
+        prover_client::cancel_copies(&proof_id, &task_id).await;
+    });
+}
+
+/// A root proof to run, else a copy of a straggling one when a node it may run on is idle
+fn next_prove_task(stage: &mut Stage) -> Option<ProveTask> {
+    let seg_size = stage.generate_context.seg_size;
+    stage.get_prove_task().or_else(|| {
+        stage.get_prove_copy(|excluded| {
+            scheduler::has_spare_node(TASK_ITYPE_PROVE, seg_size, excluded)
+        })
+    })
+}
+
+/// An aggregation to run, else a copy of a straggling one when a node it may run on is idle
+fn next_agg_task(stage: &mut Stage) -> Option<AggTask> {
+    let seg_size = stage.generate_context.seg_size;
+    stage.get_agg_task().or_else(|| {
+        stage.get_agg_copy(|excluded| scheduler::has_spare_node(TASK_ITYPE_AGG, seg_size, excluded))
+    })
+}
+
 pub fn now_timestamp() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -51,9 +133,23 @@ async fn run_stage_task(
         match serde_json::from_str::<stage::contexts::GenerateContext>(&context) {
             Ok(generate_context) => {
                 let mut check_at = now_timestamp();
-                let mut stage = stage::stage::Stage::new(generate_context.clone());
+                let mut stage = load_stage(&db, &task.id, &generate_context).await;
+                scheduler::register(&task.id, &generate_context);
+                let retry_policy = crate::config::instance()
+                    .lock()
+                    .unwrap()
+                    .retry_policy
+                    .clone();
+                if let Some(retry_policy) = retry_policy {
+                    stage.retry_policies = retry_policy;
+                }
                 let (tx, mut rx) = tokio::sync::mpsc::channel(128);
+                // cancel_proof on this instance announces the cancellation here
+                let mut events = stage_events::subscribe(&task.id);
                 stage.dispatch();
+                let mut checkpoint = true;
+                let mut checkpoint_at = 0;
+                let mut totals = (0, 0);
                 loop {
                     let current_step = stage.step.clone();
                     match stage.step {
@@ -70,29 +166,59 @@ async fn run_stage_task(
                                     }
                                 });
                             }
+                            // segments announced by the running split are proved right away
+                            let prove_task = next_prove_task(&mut stage);
+                            if let Some(prove_task) = prove_task {
+                                let tx = tx.clone();
+                                let tls_config = tls_config.clone();
+                                tokio::spawn(async move {
+                                    let response =
+                                        prover_client::prove(prove_task, tls_config, tx.clone())
+                                            .await;
+                                    if let Some(prove_task) = response {
+                                        let _ = tx.send(Task::Prove(prove_task)).await;
+                                    }
+                                });
+                            }
                         }
                         Step::InProve => {
-                            let prove_task = stage.get_prove_task();
+                            let prove_task = next_prove_task(&mut stage);
                             if let Some(prove_task) = prove_task {
                                 let tx = tx.clone();
                                 let tls_config = tls_config.clone();
                                 tokio::spawn(async move {
                                     let response =
-                                        prover_client::prove(prove_task, tls_config).await;
+                                        prover_client::prove(prove_task, tls_config, tx.clone())
+                                            .await;
                                     if let Some(prove_task) = response {
                                         let _ = tx.send(Task::Prove(prove_task)).await;
                                     }
                                 });
                             }
+                            // aggregations whose root proofs are done do not wait for the rest
+                            let agg_task = next_agg_task(&mut stage);
+                            if let Some(agg_task) = agg_task {
+                                let tx = tx.clone();
+                                let tls_config = tls_config.clone();
+                                tokio::spawn(async move {
+                                    let response =
+                                        prover_client::aggregate(agg_task, tls_config, tx.clone())
+                                            .await;
+                                    if let Some(agg_task) = response {
+                                        let _ = tx.send(Task::Agg(agg_task)).await;
+                                    }
+                                });
+                            }
                         }
                         Step::InAgg => {
-                            let agg_task = stage.get_agg_task();
+                            let agg_task = next_agg_task(&mut stage);
                             if let Some(agg_task) = agg_task {
                                 let tx = tx.clone();
                                 let tls_config = tls_config.clone();
                                 tokio::spawn(async move {
                                     let response =
-                                        prover_client::aggregate(agg_task, tls_config).await;
+                                        prover_client::aggregate(agg_task, tls_config, tx.clone())
+                                            .await;
                                     if let Some(agg_task) = response {
                                         let _ = tx.send(Task::Agg(agg_task)).await;
                                     }
@@ -114,6 +240,19 @@ async fn run_stage_task(
                                 });
                             }
                         }
+                        Step::InWrap => {
+                            let wrap_task = stage.get_wrap_task();
+                            if let Some(wrap_task) = wrap_task {
+                                let tx = tx.clone();
+                                let tls_config = tls_config.clone();
+                                tokio::spawn(async move {
+                                    let response = prover_client::wrap(wrap_task, tls_config).await;
+                                    if let Some(wrap_task) = response {
+                                        let _ = tx.send(Task::Wrap(wrap_task)).await;
+                                    }
+                                });
+                            }
+                        }
                         Step::InFinal => {
                             let final_task = stage.get_final_task();
                             if let Some(final_task) = final_task {
@@ -133,30 +272,94 @@ async fn run_stage_task(
                     tokio::select! {
                         task = rx.recv() => {
                             if let Some(task) = task {
+                                checkpoint |= !matches!(task, Task::Running(_));
                                 match task {
                                     Task::Split(mut data) => {
                                         stage.on_split_task(&mut data);
+                                        notify_task(
+                                            &stage,
+                                            TASK_ITYPE_SPLIT,
+                                            &data.task_id,
+                                            data.state,
+                                        );
                                         save_task!(data, db, TASK_ITYPE_SPLIT);
                                     },
                                     Task::Prove(mut data) => {
                                         stage.on_prove_task(&mut data);
+                                        if data.state == TASK_STATE_SUCCESS {
+                                            cancel_copies(&data.proof_id, &data.task_id);
+                                        }
+                                        notify_task(
+                                            &stage,
+                                            TASK_ITYPE_PROVE,
+                                            &data.task_id,
+                                            data.state,
+                                        );
                                         save_task!(data, db, TASK_ITYPE_PROVE);
                                     },
                                     Task::Agg(mut data) => {
                                         stage.on_agg_task(&mut data);
+                                        if data.state == TASK_STATE_SUCCESS {
+                                            cancel_copies(&data.proof_id, &data.task_id);
+                                        }
+                                        notify_task(
+                                            &stage,
+                                            TASK_ITYPE_AGG,
+                                            &data.task_id,
+                                            data.state,
+                                        );
                                         save_task!(data, db, TASK_ITYPE_AGG);
                                     },
                                     Task::AggAll(mut data) => {
                                         stage.on_agg_all_task(&mut data);
+                                        notify_task(
+                                            &stage,
+                                            TASK_ITYPE_AGGALL,
+                                            &data.task_id,
+                                            data.state,
+                                        );
                                         save_task!(data, db, TASK_ITYPE_AGGALL);
                                     },
+                                    Task::Wrap(mut data) => {
+                                        stage.on_wrap_task(&mut data);
+                                        notify_task(
+                                            &stage,
+                                            TASK_ITYPE_WRAP,
+                                            &data.task_id,
+                                            data.state,
+                                        );
+                                        save_task!(data, db, TASK_ITYPE_WRAP);
+                                    },
                                     Task::Final(mut data) => {
                                         stage.on_final_task(&mut data);
+                                        notify_task(
+                                            &stage,
+                                            TASK_ITYPE_FINAL,
+                                            &data.task_id,
+                                            data.state,
+                                        );
                                         save_task!(data, db, TASK_ITYPE_FINAL);
                                     },
+                                    Task::Running(data) => {
+                                        stage.on_task_running(&data);
+                                    },
                                 };
                             }
                         },
+                        event = events.recv() => match event {
+                            Ok(event) => {
+                                if event.status
+                                    == crate::stage_service::stage_service::Status::Cancelled as u32
+                                {
+                                    stage.cancel();
+                                    break;
+                                }
+                            }
+                            Err(RecvError::Lagged(_)) => {}
+                            Err(RecvError::Closed) => {
+                                events = stage_events::subscribe(&task.id);
+                            }
+                        },
                         () = time::sleep(time::Duration::from_secs(1)) => {
                         }
                     };
@@ -164,6 +367,34 @@ async fn run_stage_task(
                         break;
                     }
                     stage.dispatch();
+                    // task results between step transitions are batched into one checkpoint
+                    if current_step != stage.step
+                        || (checkpoint && checkpoint_at + CHECKPOINT_INTERVAL <= now_timestamp())
+                    {
+                        save_checkpoint(&db, &task.id, &stage).await;
+                        checkpoint = false;
+                        checkpoint_at = now_timestamp();
+                    }
+                    if current_step != stage.step {
+                        stage_events::publish(stage_events::new_event(
+                            &stage,
+                            crate::stage_service::stage_service::Status::Computing.into(),
+                        ));
+                    }
+                    // prove tasks are added while the split runs, agg tasks once it is done
+                    let new_totals = (stage.prove_progress().1, stage.agg_progress().1);
+                    if new_totals != totals {
+                        let updated = db
+                            .update_stage_task_progress(
+                                &task.id,
+                                new_totals.0 as i32,
+                                new_totals.1 as i32,
+                            )
+                            .await;
+                        if updated.is_ok() {
+                            totals = new_totals;
+                        }
+                    }
                     let ts_now = now_timestamp();
                     if check_at + 10 < ts_now || current_step != stage.step {
                         check_at = ts_now;
@@ -180,19 +411,45 @@ async fn run_stage_task(
                                 task.check_at = check_at as i64;
                             }
                         }
+                        // the proof may have been cancelled on another stage instance
+                        if let Ok(stage_task) = db.get_stage_task(&task.id).await {
+                            if stage_task.status
+                                == crate::stage_service::stage_service::Status::Cancelled as i32
+                            {
+                                stage.cancel();
+                                break;
+                            }
+                        }
                     }
                 }
-                if stage.is_error() {
+                scheduler::unregister(&task.id);
+                let status = if stage.is_cancelled() {
+                    prover_client::cancel_tasks(&task.id).await;
+                    log::info!("[stage] cancelled {}", task.id);
+                    crate::stage_service::stage_service::Status::Cancelled
+                } else if stage.is_error() {
                     let get_status = || match stage.step {
                         Step::InSplit => crate::stage_service::stage_service::Status::SplitError,
                         Step::InProve => crate::stage_service::stage_service::Status::ProveError,
                         Step::InAgg => crate::stage_service::stage_service::Status::AggError,
                         Step::InAggAll => crate::stage_service::stage_service::Status::AggError,
+                        Step::InWrap => crate::stage_service::stage_service::Status::WrapError,
                         Step::InFinal => crate::stage_service::stage_service::Status::FinalError,
                         _ => crate::stage_service::stage_service::Status::InternalError,
                     };
                     let status = get_status();
-                    let _ = db.update_stage_task(&task.id, status.into(), "").await;
+                    log::error!("[stage] {} failed: {}", task.id, stage.errmsg);
+                    // keep the failed state so that RetryProof resumes from the failing step
+                    save_checkpoint(&db, &task.id, &stage).await;
+                    let _ = db
+                        .update_stage_task_error(
+                            &task.id,
+                            status.into(),
+                            &stage.errmsg,
+                            crate::stage_service::stage_service::Status::Cancelled.into(),
+                        )
+                        .await;
+                    status
                 } else {
                     let result = if generate_context.execute_only || generate_context.precompile {
                         vec![]
@@ -207,14 +464,19 @@ async fn run_stage_task(
                         )
                         .await;
                     log::info!("[stage] finished {} ", stage.timecost_string());
-                }
+                    crate::stage_service::stage_service::Status::Success
+                };
+                stage_events::close(stage_events::new_event(&stage, status.into()));
+                drop(events);
+                stage_events::release(&task.id);
             }
-            Err(_) => {
+            Err(e) => {
                 let _ = db
-                    .update_stage_task(
+                    .update_stage_task_error(
                         &task.id,
                         crate::stage_service::stage_service::Status::InternalError.into(),
-                        "",
+                        &format!("invalid generate context: {}", e),
+                        crate::stage_service::stage_service::Status::Cancelled.into(),
                     )
                     .await;
             }
@@ -275,7 +537,27 @@ async fn load_stage_task(tls_config: Option<TlsConfig>, db: database::Database)
     }
 }
 
+/// Drop the registered prover nodes that stopped sending heartbeats. The tasks they were
+/// running stop waiting for them and go back to the queue.
+async fn drop_expired_nodes() {
+    loop {
+        let expired = crate::prover_node::instance()
+            .lock()
+            .unwrap()
+            .drop_expired();
+        for addr in expired {
+            log::warn!("[stage] drop prover node {}, missed heartbeats", addr);
+        }
+        time::sleep(time::Duration::from_secs(
+            crate::prover_node::HEARTBEAT_INTERVAL_SECS,
+        ))
+        .await;
+    }
+}
+
 pub async fn start(tls_config: Option<TlsConfig>, db: database::Database) -> anyhow::Result<bool> {
+    scheduler::start(tls_config.clone()).await;
+    tokio::spawn(drop_expired_nodes());
     tokio::spawn(async move {
         load_stage_task(tls_config, db).await;
     });
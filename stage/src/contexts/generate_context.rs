@@ -1,3 +1,4 @@
+use crate::stage::AggStrategy;
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -14,10 +15,24 @@ pub struct GenerateContext {
     pub output_stream_path: String,
     pub block_no: u64,
     pub seg_size: u32,
+    // cycles the split cuts segments at, 0 for seg_size. Set when a run is split again, the
+    // segments are still proved with the circuits of seg_size.
+    #[serde(default)]
+    pub split_seg_size: u32,
     pub execute_only: bool,
     pub precompile: bool,
     pub receipt_inputs_path: String,
     pub receipts_path: String,
+    #[serde(default)]
+    pub agg_strategy: AggStrategy,
+    #[serde(default)]
+    pub user_address: String,
+    // higher runs first
+    #[serde(default)]
+    pub priority: i32,
+    // unix seconds, 0 for no deadline
+    #[serde(default)]
+    pub deadline_ts: u64,
 }
 
 impl GenerateContext {
@@ -53,10 +68,15 @@ impl GenerateContext {
             output_stream_path: output_stream_path.to_string(),
             block_no,
             seg_size,
+            split_seg_size: 0,
             execute_only,
             precompile,
             receipt_inputs_path: receipt_inputs_path.to_string(),
             receipts_path: receipts_path.to_string(),
+            agg_strategy: AggStrategy::default(),
+            user_address: "".to_string(),
+            priority: 0,
+            deadline_ts: 0,
         }
     }
 }
@@ -1,20 +1,36 @@
 use crate::contexts::generate_context::GenerateContext;
 use crate::tasks::agg_task::{self, AggTask};
 use crate::tasks::TASK_STATE_PROCESSING;
-use crate::tasks::{AggAllTask, FinalTask, ProveTask, SplitTask};
+use crate::tasks::{AggAllTask, FinalTask, ProveTask, RunningTask, SplitTask, WrapTask};
+use crate::tasks::{TASK_ITYPE_AGG, TASK_ITYPE_PROVE};
 use crate::tasks::{
     TASK_STATE_FAILED, TASK_STATE_INITIAL, TASK_STATE_SUCCESS, TASK_STATE_UNPROCESSED,
 };
 use common::file;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// A running task is a straggler once it takes this many times the median time cost of its
+/// finished siblings, it is then duplicated on another node
+pub const STRAGGLER_FACTOR: u64 = 3;
+pub const STRAGGLER_MIN_FINISHED: usize = 3;
+pub const STRAGGLER_MIN_SECS: u64 = 60;
+/// Appended to the task id of a speculative copy, the copy is a job of its own on the prover
+/// node and a task of its own in the database
+pub const SPECULATIVE_SUFFIX: &str = "-spec";
+
+/// Id of the task a speculative copy runs for, the id itself for any other task
+pub fn original_task_id(task_id: &str) -> &str {
+    task_id.strip_suffix(SPECULATIVE_SUFFIX).unwrap_or(task_id)
+}
+
 pub fn get_timestamp() -> u64 {
     let now = SystemTime::now();
     let duration_since_epoch = now.duration_since(UNIX_EPOCH).unwrap();
     duration_since_epoch.as_secs()
 }
-#[derive(Default, PartialEq, Clone)]
+#[derive(Default, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Step {
     #[default]
     Init,
@@ -22,6 +38,7 @@ pub enum Step {
     InProve,
     InAgg,
     InAggAll,
+    InWrap,
     InFinal,
     End,
 }
@@ -36,25 +53,121 @@ impl From<Step> for i32 {
             Step::InAggAll => 4,
             Step::InFinal => 5,
             Step::End => 6,
+            // added after the others, the stored and reported values of the steps never move
+            Step::InWrap => 7,
+        }
+    }
+}
+
+/// How a task of one type is retried before the whole proof is failed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one, 1 disables retrying
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled for every following attempt
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    /// Prefer another prover node after a node failed the task
+    pub exclude_failed_node: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff_secs: 5,
+            max_backoff_secs: 300,
+            exclude_failed_node: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Seconds to wait after the given number of finished attempts
+    pub fn backoff(&self, attempts: u32) -> u64 {
+        let exp = attempts.saturating_sub(1).min(31);
+        self.backoff_secs
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff_secs)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicies {
+    pub split: RetryPolicy,
+    pub prove: RetryPolicy,
+    pub agg: RetryPolicy,
+    pub agg_all: RetryPolicy,
+    pub wrap: RetryPolicy,
+    pub final_proof: RetryPolicy,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggTreeShape {
+    /// Pairwise aggregation, the shortest path to the final proof
+    #[default]
+    Balanced,
+    /// One chain of aggregations, a single agg node at a time
+    LeftDeep,
+}
+
+/// How the root proofs of a proof are aggregated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AggStrategy {
+    /// Up to this many root proofs are aggregated by a single agg_all task
+    pub agg_all_max_proofs: usize,
+    pub shape: AggTreeShape,
+    /// Max aggregations at the bottom level of a balanced tree, 0 for no limit. Above the
+    /// limit the root proofs are aggregated in that many left-deep chains.
+    pub max_width: usize,
+}
+
+impl Default for AggStrategy {
+    fn default() -> Self {
+        AggStrategy {
+            agg_all_max_proofs: 3,
+            shape: AggTreeShape::Balanced,
+            max_width: 0,
+        }
+    }
+}
+
+impl AggStrategy {
+    /// Number of left-deep chains the root proofs are split into
+    fn chains(&self, proofs: usize) -> usize {
+        let width = proofs.div_ceil(2);
+        match self.shape {
+            AggTreeShape::LeftDeep => 1,
+            AggTreeShape::Balanced if self.max_width > 0 => width.min(self.max_width),
+            AggTreeShape::Balanced => width,
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct Stage {
     pub generate_context: GenerateContext,
     pub split_task: SplitTask,
     pub prove_tasks: Vec<ProveTask>,
     pub agg_tasks: Vec<AggTask>,
     pub agg_all_task: AggAllTask,
+    #[serde(default)]
+    pub wrap_task: WrapTask,
     pub final_task: FinalTask,
     pub is_error: bool,
+    pub is_cancelled: bool,
     pub errmsg: String,
     pub step: Step,
+    #[serde(default)]
+    pub retry_policies: RetryPolicies,
 }
 
 macro_rules! on_task {
-    ($src:ident, $dst:ident, $stage:ident) => {
+    ($src:ident, $dst:ident, $stage:ident, $name:expr, $policy:ident) => {
         assert!($src.proof_id == $dst.proof_id);
         if $src.state == TASK_STATE_FAILED
             || $src.state == TASK_STATE_SUCCESS
@@ -65,9 +178,51 @@ macro_rules! on_task {
                 log::debug!("on_task {:#?}", $dst);
                 $dst.finish_ts = get_timestamp();
                 $src.finish_ts = $dst.finish_ts;
+                $dst.node_info.clone_from(&$src.node_info);
+                $dst.errmsg.clone_from(&$src.errmsg);
+            }
+            // a node answering BUSY (or draining) is backed off without using up an attempt, an
+            // empty node_info means no node was idle
+            if TASK_STATE_UNPROCESSED == $src.state && !$src.node_info.is_empty() {
+                let policy = &$stage.retry_policies.$policy;
+                $dst.retry_at = get_timestamp() + policy.backoff(1);
+                log::info!(
+                    "{} task {} node {} busy, retry at {}",
+                    $name,
+                    $src.task_id,
+                    $src.node_info,
+                    $dst.retry_at
+                );
             }
             if TASK_STATE_FAILED == $src.state {
-                $stage.is_error = true;
+                let policy = &$stage.retry_policies.$policy;
+                $dst.attempts += 1;
+                if $dst.attempts < policy.max_attempts {
+                    $dst.state = TASK_STATE_UNPROCESSED;
+                    $dst.retry_at = get_timestamp() + policy.backoff($dst.attempts);
+                    if policy.exclude_failed_node && !$dst.excluded_nodes.contains(&$src.node_info)
+                    {
+                        $dst.excluded_nodes.push($src.node_info.clone());
+                    }
+                    log::warn!(
+                        "{} task {} attempt {} failed on {}: {}, retry at {}",
+                        $name,
+                        $src.task_id,
+                        $dst.attempts,
+                        $src.node_info,
+                        $src.errmsg,
+                        $dst.retry_at
+                    );
+                } else {
+                    $dst.state = TASK_STATE_FAILED;
+                    $stage.is_error = true;
+                    $stage.errmsg = format!(
+                        "{} task {} failed on {}: {}",
+                        $name, $src.task_id, $src.node_info, $dst.errmsg
+                    );
+                }
+                $src.state = $dst.state;
+                $src.attempts = $dst.attempts;
             }
         }
     };
@@ -75,7 +230,9 @@ macro_rules! on_task {
 
 macro_rules! get_task {
     ($src:ident) => {
-        if $src.state == TASK_STATE_UNPROCESSED || $src.state == TASK_STATE_FAILED {
+        if ($src.state == TASK_STATE_UNPROCESSED || $src.state == TASK_STATE_FAILED)
+            && $src.retry_at <= get_timestamp()
+        {
             $src.state = TASK_STATE_PROCESSING;
             $src.start_ts = get_timestamp();
             return Some($src.clone());
@@ -92,14 +249,20 @@ impl Stage {
             prove_tasks: Vec::new(),
             agg_tasks: Vec::new(),
             agg_all_task: AggAllTask::default(),
+            wrap_task: WrapTask::default(),
             final_task: FinalTask::default(),
             is_error: false,
+            is_cancelled: false,
             errmsg: "".to_string(),
             step: Step::Init,
+            retry_policies: RetryPolicies::default(),
         }
     }
 
     pub fn dispatch(&mut self) {
+        if self.is_cancelled {
+            return;
+        }
         match self.step {
             Step::Init => {
                 self.gen_split_task();
@@ -111,52 +274,128 @@ impl Stage {
                         self.step = Step::End;
                     } else {
                         self.gen_prove_task();
+                        // root proofs that waited for their segment to be split again
+                        for prove_task in &mut self.prove_tasks {
+                            if prove_task.state == TASK_STATE_INITIAL {
+                                prove_task.state = TASK_STATE_UNPROCESSED;
+                            }
+                        }
+                        if self.prove_tasks.is_empty()
+                            || (self.prove_tasks.len() == 1 && !self.generate_context.precompile)
+                        {
+                            match self.resplit() {
+                                Ok(true) => return,
+                                // a precompile proves a lone segment, aggregation needs two
+                                Ok(false) if self.generate_context.precompile => {}
+                                Ok(false) => {
+                                    self.is_error = true;
+                                    self.errmsg = format!(
+                                        "Segment count is {}, the program is too short to prove",
+                                        self.prove_tasks.len()
+                                    );
+                                    return;
+                                }
+                                Err(errmsg) => {
+                                    self.is_error = true;
+                                    self.errmsg = errmsg;
+                                    return;
+                                }
+                            }
+                        }
+                        // aggregation starts as soon as the sibling root proofs are done
+                        if !self.generate_context.precompile
+                            && self.prove_tasks.len()
+                                > self.generate_context.agg_strategy.agg_all_max_proofs
+                            && self.agg_tasks.is_empty()
+                        {
+                            self.gen_agg_tasks();
+                        }
                         self.step = Step::InProve;
                     }
+                } else if !self.generate_context.execute_only {
+                    self.gen_split_prove_tasks();
                 }
             }
-            Step::InProve => {
+            Step::InProve
                 if self
                     .prove_tasks
                     .iter()
-                    .all(|task| task.state == TASK_STATE_SUCCESS)
+                    .all(|task| task.state == TASK_STATE_SUCCESS) =>
+            {
+                if self.generate_context.precompile {
+                    self.step = Step::End;
+                } else if self.prove_tasks.len()
+                    > self.generate_context.agg_strategy.agg_all_max_proofs
                 {
-                    if self.generate_context.precompile {
-                        self.step = Step::End;
-                    } else if self.prove_tasks.len() > 3 {
+                    if self.agg_tasks.is_empty() {
                         self.gen_agg_tasks();
-                        self.step = Step::InAgg;
-                    } else {
-                        self.gen_agg_all_task();
-                        self.step = Step::InAggAll;
                     }
+                    self.step = Step::InAgg;
+                } else {
+                    self.gen_agg_all_task();
+                    self.step = Step::InAggAll;
                 }
             }
-            Step::InAgg => {
+            Step::InAgg
                 if self
                     .agg_tasks
                     .iter()
-                    .all(|task| task.state == TASK_STATE_SUCCESS)
-                {
-                    self.gen_final_task();
-                    self.step = Step::InFinal;
-                }
+                    .all(|task| task.state == TASK_STATE_SUCCESS) =>
+            {
+                self.gen_wrap_task();
+                self.step = Step::InWrap;
             }
-            Step::InAggAll => {
-                if self.agg_all_task.state == TASK_STATE_SUCCESS {
-                    self.gen_final_task();
-                    self.step = Step::InFinal;
-                }
+            Step::InAggAll if self.agg_all_task.state == TASK_STATE_SUCCESS => {
+                self.gen_wrap_task();
+                self.step = Step::InWrap;
             }
-            Step::InFinal => {
-                if self.final_task.state == TASK_STATE_SUCCESS {
-                    self.step = Step::End;
-                }
+            Step::InWrap if self.wrap_task.state == TASK_STATE_SUCCESS => {
+                self.gen_final_task();
+                self.step = Step::InFinal;
+            }
+            Step::InFinal if self.final_task.state == TASK_STATE_SUCCESS => {
+                self.step = Step::End;
             }
             _ => {}
         }
     }
 
+    /// Prepare a stage restored from a checkpoint: tasks that were in flight when the
+    /// checkpoint was written lost their RPC, so they are handed out again. Failed tasks
+    /// are reset as well, which is how a failed proof gets retried from its failing step.
+    pub fn resume(&mut self) {
+        let mut retried = false;
+        macro_rules! reset {
+            ($task:expr) => {
+                if $task.state == TASK_STATE_PROCESSING || $task.state == TASK_STATE_FAILED {
+                    if $task.state == TASK_STATE_FAILED {
+                        retried = true;
+                        $task.attempts = 0;
+                        $task.retry_at = 0;
+                    }
+                    $task.state = TASK_STATE_UNPROCESSED;
+                    $task.errmsg.clear();
+                }
+            };
+        }
+        reset!(self.split_task);
+        self.prove_tasks.iter_mut().for_each(|task| {
+            task.speculative = false;
+            reset!(task)
+        });
+        self.agg_tasks.iter_mut().for_each(|task| {
+            task.speculative = false;
+            reset!(task)
+        });
+        reset!(self.agg_all_task);
+        reset!(self.wrap_task);
+        reset!(self.final_task);
+        if retried {
+            self.is_error = false;
+            self.errmsg.clear();
+        }
+    }
+
     pub fn is_success(&mut self) -> bool {
         if self.step == Step::End || self.final_task.state == TASK_STATE_SUCCESS {
             return true;
@@ -168,6 +407,14 @@ impl Stage {
         self.is_error
     }
 
+    pub fn cancel(&mut self) {
+        self.is_cancelled = true;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.is_cancelled
+    }
+
     fn gen_split_task(&mut self) {
         assert!(self.split_task.state == TASK_STATE_INITIAL);
         self.split_task
@@ -192,7 +439,10 @@ impl Stage {
             .output_path
             .clone_from(&self.generate_context.output_stream_path);
         self.split_task.block_no = self.generate_context.block_no;
-        self.split_task.seg_size = self.generate_context.seg_size;
+        self.split_task.seg_size = match self.generate_context.split_seg_size {
+            0 => self.generate_context.seg_size,
+            split_seg_size => split_seg_size,
+        };
         self.split_task.task_id = uuid::Uuid::new_v4().to_string();
         self.split_task.state = TASK_STATE_UNPROCESSED;
         self.split_task
@@ -209,7 +459,53 @@ impl Stage {
     pub fn on_split_task(&mut self, split_task: &mut SplitTask) {
         let dst = &mut self.split_task;
         dst.total_steps = split_task.total_steps;
-        on_task!(split_task, dst, self);
+        on_task!(split_task, dst, self, "split", split);
+    }
+
+    fn new_prove_task(&self, file_no: usize) -> ProveTask {
+        ProveTask {
+            file_no,
+            task_id: uuid::Uuid::new_v4().to_string(),
+            base_dir: self.generate_context.basedir.clone(),
+            block_no: self.generate_context.block_no,
+            state: TASK_STATE_UNPROCESSED,
+            seg_size: self.generate_context.seg_size,
+            proof_id: self.generate_context.proof_id.clone(),
+            receipt_path: format!("{}/receipt/{}", self.generate_context.prove_path, file_no),
+            seg_path: format!("{}/{}", self.generate_context.seg_path, file_no),
+            start_ts: 0,
+            finish_ts: 0,
+            node_info: "".to_string(),
+            errmsg: "".to_string(),
+            attempts: 0,
+            retry_at: 0,
+            excluded_nodes: Vec::new(),
+            speculative: false,
+            receipts_path: self.generate_context.receipts_path.clone(),
+        }
+    }
+
+    /// Create prove tasks for the segments the running split task has announced in its manifest
+    fn gen_split_prove_tasks(&mut self) {
+        let manifest = format!(
+            "{}/{}",
+            self.generate_context.seg_path,
+            file::SEGMENT_MANIFEST
+        );
+        let segments = match file::new(&manifest).read_to_string() {
+            Ok(content) => content.trim().parse::<usize>().unwrap_or(0),
+            Err(_) => return,
+        };
+        if segments > 0 && self.prove_tasks.is_empty() {
+            file::new(&self.generate_context.prove_path)
+                .create_dir_all()
+                .unwrap();
+        }
+        // segments are announced in order, the tasks created so far are 0..len
+        for file_no in self.prove_tasks.len()..segments {
+            let prove_task = self.new_prove_task(file_no);
+            self.prove_tasks.push(prove_task);
+        }
     }
 
     fn gen_prove_task(&mut self) {
@@ -218,82 +514,232 @@ impl Stage {
         let files = file::new(&self.generate_context.seg_path)
             .read_dir()
             .unwrap();
+        let split_prove_tasks = self.prove_tasks.len();
         for file_name in files {
             let result: Result<usize, <usize as FromStr>::Err> = file_name.parse();
             if let Ok(file_no) = result {
-                let prove_task = ProveTask {
-                    file_no,
-                    task_id: uuid::Uuid::new_v4().to_string(),
-                    base_dir: self.generate_context.basedir.clone(),
-                    block_no: self.generate_context.block_no,
-                    state: TASK_STATE_UNPROCESSED,
-                    seg_size: self.generate_context.seg_size,
-                    proof_id: self.generate_context.proof_id.clone(),
-                    receipt_path: format!("{}/receipt/{}", prove_dir.clone(), file_no),
-                    seg_path: format!("{}/{}", self.generate_context.seg_path, file_name),
-                    start_ts: 0,
-                    finish_ts: 0,
-                    node_info: "".to_string(),
-                    receipts_path: self.generate_context.receipts_path.clone(),
-                };
+                if file_no < split_prove_tasks {
+                    continue;
+                }
+                let prove_task = self.new_prove_task(file_no);
                 self.prove_tasks.push(prove_task);
             }
         }
         self.prove_tasks.sort_by_key(|p| p.file_no);
         log::debug!("gen_prove_task {:#?}", self.prove_tasks);
+    }
 
-        if self.prove_tasks.len() < 2 {
-            self.is_error = true;
-            self.errmsg = format!(
-                "Segment count is {}, please reduce SEG_SIZE !",
-                self.prove_tasks.len()
-            );
+    /// Split the run again into segments small enough for at least two root proofs, the
+    /// aggregation can not take a single one. Only the split cuts smaller segments, they are
+    /// proved with the circuits of seg_size like the last segment of any run. The new
+    /// segments and receipts go to their own dirs, a root proof of the old segment may still
+    /// be running. False when the run is too short for two segments.
+    fn resplit(&mut self) -> std::result::Result<bool, String> {
+        let steps = self.split_task.total_steps.min(u32::MAX as u64 / 2) as u32;
+        let current = match self.generate_context.split_seg_size {
+            0 => self.generate_context.seg_size,
+            split_seg_size => split_seg_size,
+        };
+        // the first segments are split at a quarter of the size
+        let split_seg_size = 1 << (31 - (steps * 2).max(1).leading_zeros());
+        if split_seg_size >= current || split_seg_size < 4 {
+            return Ok(false);
         }
+        log::info!(
+            "split {} into {} segment of {} steps, split again at {} cycles",
+            self.generate_context.proof_id,
+            self.prove_tasks.len(),
+            self.split_task.total_steps,
+            split_seg_size
+        );
+        self.generate_context.split_seg_size = split_seg_size;
+        self.generate_context.seg_path = format!(
+            "{}/resplit_{}",
+            self.generate_context.seg_path, split_seg_size
+        );
+        self.generate_context.prove_path = format!(
+            "{}/resplit_{}",
+            self.generate_context.prove_path, split_seg_size
+        );
+        let receipt_dir = format!("{}/receipt", self.generate_context.prove_path);
+        if let Err(e) = file::new(&receipt_dir).create_dir_all() {
+            return Err(format!("create {} failed: {}", receipt_dir, e));
+        }
+        self.prove_tasks.clear();
+        self.split_task = SplitTask::default();
+        self.gen_split_task();
+        Ok(true)
     }
 
     pub fn get_prove_task(&mut self) -> Option<ProveTask> {
+        let now = get_timestamp();
         for prove_task in &mut self.prove_tasks {
-            if prove_task.state == TASK_STATE_UNPROCESSED || prove_task.state == TASK_STATE_FAILED {
+            if (prove_task.state == TASK_STATE_UNPROCESSED || prove_task.state == TASK_STATE_FAILED)
+                && prove_task.retry_at <= now
+            {
                 prove_task.state = TASK_STATE_PROCESSING;
                 prove_task.start_ts = get_timestamp();
+                prove_task.node_info.clear();
                 return Some(prove_task.clone());
             }
         }
         None
     }
 
+    /// A copy of a straggling root proof, only started when `spare_node` finds an idle node
+    /// outside of the excluded ones of the copy
+    pub fn get_prove_copy(&mut self, spare_node: impl Fn(&[String]) -> bool) -> Option<ProveTask> {
+        let now = get_timestamp();
+        // segments are aggregated by index when there is no agg tree, a copy cannot write
+        // its receipt elsewhere
+        if self.prove_tasks.len() <= self.generate_context.agg_strategy.agg_all_max_proofs {
+            return None;
+        }
+        let threshold = straggler_threshold(
+            self.prove_tasks
+                .iter()
+                .filter(|task| task.state == TASK_STATE_SUCCESS)
+                .map(|task| task.finish_ts - task.start_ts),
+        )?;
+        for prove_task in &mut self.prove_tasks {
+            // a task still waiting for a node is not running late
+            if prove_task.state == TASK_STATE_PROCESSING
+                && !prove_task.speculative
+                && !prove_task.node_info.is_empty()
+                && now.saturating_sub(prove_task.start_ts) > threshold
+            {
+                let mut task = prove_task.clone();
+                task.task_id = format!("{}{}", prove_task.task_id, SPECULATIVE_SUFFIX);
+                task.receipt_path = format!("{}.speculative", prove_task.receipt_path);
+                if !task.excluded_nodes.contains(&prove_task.node_info) {
+                    task.excluded_nodes.push(prove_task.node_info.clone());
+                }
+                if !spare_node(&task.excluded_nodes) {
+                    continue;
+                }
+                prove_task.speculative = true;
+                task.speculative = true;
+                log::warn!(
+                    "prove task {} is running {} sec, start a speculative copy",
+                    task.task_id,
+                    now.saturating_sub(task.start_ts)
+                );
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    /// A prover node took the task. Stragglers are measured from here and their copies
+    /// run on another node.
+    pub fn on_task_running(&mut self, running: &RunningTask) {
+        if running.speculative {
+            return;
+        }
+        let task = match running.itype {
+            TASK_ITYPE_PROVE => self
+                .prove_tasks
+                .iter_mut()
+                .find(|task| task.task_id == running.task_id)
+                .map(|task| (&mut task.state, &mut task.start_ts, &mut task.node_info)),
+            TASK_ITYPE_AGG => self
+                .agg_tasks
+                .iter_mut()
+                .find(|task| task.task_id == running.task_id)
+                .map(|task| (&mut task.state, &mut task.start_ts, &mut task.node_info)),
+            _ => None,
+        };
+        if let Some((state, start_ts, node_info)) = task {
+            if *state == TASK_STATE_PROCESSING {
+                *start_ts = running.start_ts;
+                node_info.clone_from(&running.node_info);
+            }
+        }
+    }
+
     pub fn on_prove_task(&mut self, prove_task: &mut ProveTask) {
+        // the result of a copy goes to the task it runs for
+        let task_id = original_task_id(&prove_task.task_id).to_string();
+        if let Some(index) = self.prove_tasks.iter().position(|task| {
+            task.task_id == task_id && task.state == TASK_STATE_PROCESSING && !task.speculative
+        }) {
+            let attempts = self.prove_tasks[index].attempts + 1;
+            if attempts < self.retry_policies.prove.max_attempts
+                && self
+                    .regenerate_input(prove_task.state, &prove_task.errmsg)
+                    .is_some()
+            {
+                // the root proof waits for the split to write the segment again
+                let task = &mut self.prove_tasks[index];
+                task.state = TASK_STATE_INITIAL;
+                task.attempts = attempts;
+                task.errmsg.clone_from(&prove_task.errmsg);
+                prove_task.state = task.state;
+                prove_task.attempts = attempts;
+                return;
+            }
+        }
+        let mut moved_receipt = None;
         for mut item_task in &mut self.prove_tasks {
-            if item_task.task_id == prove_task.task_id && item_task.state == TASK_STATE_PROCESSING {
+            if item_task.task_id == task_id && item_task.state == TASK_STATE_PROCESSING {
+                if item_task.speculative && prove_task.state != TASK_STATE_SUCCESS {
+                    // the other copy is still running, wait for its result
+                    item_task.speculative = false;
+                    prove_task.state = TASK_STATE_PROCESSING;
+                    break;
+                }
+                if prove_task.state == TASK_STATE_SUCCESS
+                    && item_task.receipt_path != prove_task.receipt_path
+                {
+                    moved_receipt = Some(std::mem::replace(
+                        &mut item_task.receipt_path,
+                        prove_task.receipt_path.clone(),
+                    ));
+                }
                 let dst = &mut item_task;
-                on_task!(prove_task, dst, self);
+                on_task!(
+                    prove_task,
+                    dst,
+                    self,
+                    format!("prove segment {}", prove_task.file_no),
+                    prove
+                );
                 break;
             }
         }
+        if let Some(old_path) = moved_receipt {
+            self.move_receipt(&old_path, &prove_task.receipt_path);
+        }
+        if prove_task.state == TASK_STATE_SUCCESS {
+            self.clear_agg_dependency(&task_id);
+        }
     }
 
     pub fn gen_agg_tasks(&mut self) {
+        let prove_dir = self.generate_context.prove_path.clone();
+        let leaves: Vec<AggTask> = self
+            .prove_tasks
+            .iter()
+            .map(agg_task::AggTask::init_from_single_prove_task)
+            .collect();
+        let chains = self.generate_context.agg_strategy.chains(leaves.len());
         let mut agg_index = 0;
-        let mut result = Vec::new();
-        let mut current_length = self.prove_tasks.len();
-        for i in (0..current_length - 1).step_by(2) {
-            agg_index += 1;
-            result.push(agg_task::AggTask::init_from_two_prove_task(
-                &(self.prove_tasks[i]),
-                &(self.prove_tasks[i + 1]),
-                &self.generate_context.prove_path,
-                agg_index,
-            ));
-        }
-        if current_length % 2 == 1 {
-            result.push(agg_task::AggTask::init_from_single_prove_task(
-                &(self.prove_tasks[current_length - 1]),
-                &self.generate_context.prove_path,
-            ));
-        }
-        self.agg_tasks.append(&mut result.clone());
-
-        current_length = result.len();
+        let mut result: Vec<AggTask> = leaves
+            .chunks(leaves.len().div_ceil(chains))
+            .map(|chain| {
+                let mut root = chain[0].clone();
+                for leaf in &chain[1..] {
+                    agg_index += 1;
+                    root = agg_task::AggTask::init_from_two_agg_task(
+                        &root, leaf, &prove_dir, agg_index,
+                    );
+                    self.agg_tasks.push(root.clone());
+                }
+                root
+            })
+            .collect();
+
+        let mut current_length = result.len();
         while current_length > 1 {
             let mut new_result = Vec::new();
             for i in (0..current_length - 1).step_by(2) {
@@ -301,7 +747,7 @@ impl Stage {
                 let agg_task = agg_task::AggTask::init_from_two_agg_task(
                     &result[i],
                     &result[i + 1],
-                    &self.generate_context.prove_path,
+                    &prove_dir,
                     agg_index,
                 );
                 self.agg_tasks.push(agg_task.clone());
@@ -315,37 +761,214 @@ impl Stage {
         }
         let last_agg_tasks = self.agg_tasks.len() - 1;
         self.agg_tasks[last_agg_tasks].is_final = true;
-        self.agg_tasks[last_agg_tasks]
-            .output_dir
-            .clone_from(&self.generate_context.agg_path);
+        let proved: Vec<String> = self
+            .prove_tasks
+            .iter()
+            .filter(|task| task.state == TASK_STATE_SUCCESS)
+            .map(|task| task.task_id.clone())
+            .collect();
+        for task_id in proved {
+            self.clear_agg_dependency(&task_id);
+        }
         log::debug!("gen_agg_task {:#?}", self.agg_tasks);
     }
 
     pub fn get_agg_task(&mut self) -> Option<AggTask> {
+        let now = get_timestamp();
         for agg_task in &mut self.agg_tasks {
-            if agg_task.left.is_some() || agg_task.right.is_some() {
+            if agg_task.left.is_some() || agg_task.right.is_some() || agg_task.retry_at > now {
                 continue;
             }
             if agg_task.state == TASK_STATE_UNPROCESSED || agg_task.state == TASK_STATE_FAILED {
                 agg_task.state = TASK_STATE_PROCESSING;
                 agg_task.start_ts = get_timestamp();
+                agg_task.node_info.clear();
                 return Some(agg_task.clone());
             }
         }
         None
     }
 
+    /// A copy of a straggling aggregation, only started when `spare_node` finds an idle node
+    /// outside of the excluded ones of the copy
+    pub fn get_agg_copy(&mut self, spare_node: impl Fn(&[String]) -> bool) -> Option<AggTask> {
+        let now = get_timestamp();
+        let threshold = straggler_threshold(
+            self.agg_tasks
+                .iter()
+                .filter(|task| !task.from_prove && task.state == TASK_STATE_SUCCESS)
+                .map(|task| task.finish_ts - task.start_ts),
+        )?;
+        for agg_task in &mut self.agg_tasks {
+            if agg_task.state == TASK_STATE_PROCESSING
+                && !agg_task.speculative
+                && !agg_task.node_info.is_empty()
+                && now.saturating_sub(agg_task.start_ts) > threshold
+            {
+                let mut task = agg_task.clone();
+                task.task_id = format!("{}{}", agg_task.task_id, SPECULATIVE_SUFFIX);
+                task.output_receipt_path = format!("{}.speculative", agg_task.output_receipt_path);
+                if !task.excluded_nodes.contains(&agg_task.node_info) {
+                    task.excluded_nodes.push(agg_task.node_info.clone());
+                }
+                if !spare_node(&task.excluded_nodes) {
+                    continue;
+                }
+                agg_task.speculative = true;
+                task.speculative = true;
+                log::warn!(
+                    "agg task {} is running {} sec, start a speculative copy",
+                    task.task_id,
+                    now.saturating_sub(task.start_ts)
+                );
+                return Some(task);
+            }
+        }
+        None
+    }
+
     pub fn on_agg_task(&mut self, agg_task: &mut AggTask) {
+        // the result of a copy goes to the task it runs for
+        let task_id = original_task_id(&agg_task.task_id).to_string();
+        if let Some(index) = self.agg_tasks.iter().position(|task| {
+            task.task_id == task_id && task.state == TASK_STATE_PROCESSING && !task.speculative
+        }) {
+            let attempts = self.agg_tasks[index].attempts + 1;
+            if attempts < self.retry_policies.agg.max_attempts {
+                if let Some(producer) = self.regenerate_input(agg_task.state, &agg_task.errmsg) {
+                    // the aggregation waits for the input to be written again
+                    let task = &mut self.agg_tasks[index];
+                    if file::corrupt_artifact_path(&agg_task.errmsg)
+                        == Some(task.input1.receipt_path.as_str())
+                    {
+                        task.left = Some(producer);
+                    } else {
+                        task.right = Some(producer);
+                    }
+                    task.state = TASK_STATE_UNPROCESSED;
+                    task.attempts = attempts;
+                    task.errmsg.clone_from(&agg_task.errmsg);
+                    agg_task.state = task.state;
+                    agg_task.attempts = attempts;
+                    return;
+                }
+            }
+        }
+        let mut moved_receipt = None;
         for item_task in &mut self.agg_tasks {
-            if item_task.task_id == agg_task.task_id && item_task.state == TASK_STATE_PROCESSING {
-                on_task!(agg_task, item_task, self);
+            if item_task.task_id == task_id && item_task.state == TASK_STATE_PROCESSING {
+                if item_task.speculative && agg_task.state != TASK_STATE_SUCCESS {
+                    // the other copy is still running, wait for its result
+                    item_task.speculative = false;
+                    agg_task.state = TASK_STATE_PROCESSING;
+                    break;
+                }
+                if agg_task.state == TASK_STATE_SUCCESS
+                    && item_task.output_receipt_path != agg_task.output_receipt_path
+                {
+                    moved_receipt = Some(std::mem::replace(
+                        &mut item_task.output_receipt_path,
+                        agg_task.output_receipt_path.clone(),
+                    ));
+                }
+                on_task!(
+                    agg_task,
+                    item_task,
+                    self,
+                    format!("agg {}", agg_task.file_key),
+                    agg
+                );
                 break;
             }
         }
+        if let Some(old_path) = moved_receipt {
+            self.move_receipt(&old_path, &agg_task.output_receipt_path);
+        }
         if agg_task.state == TASK_STATE_SUCCESS {
-            for item_task in &mut self.agg_tasks {
-                if item_task.clear_child_task(&agg_task.task_id) {
-                    break;
+            self.clear_agg_dependency(&task_id);
+        }
+    }
+
+    /// A task failed on an input that does not match its checksum: run the task that wrote
+    /// the input again. Returns the id of that task, None when the task failed otherwise or
+    /// no task of this proof wrote the input.
+    fn regenerate_input(&mut self, state: u32, errmsg: &str) -> Option<String> {
+        if state != TASK_STATE_FAILED {
+            return None;
+        }
+        let path = file::corrupt_artifact_path(errmsg)?;
+        if self.prove_tasks.iter().any(|task| task.seg_path == path) {
+            // segments are written by the split, one that is still running writes it again
+            if self.split_task.state == TASK_STATE_SUCCESS {
+                log::warn!("segment {} is corrupt, split again", path);
+                self.split_task = SplitTask::default();
+                self.gen_split_task();
+                self.step = Step::InSplit;
+            }
+            return Some(self.split_task.task_id.clone());
+        }
+        if let Some(task) = self
+            .prove_tasks
+            .iter_mut()
+            .find(|task| task.receipt_path == path)
+        {
+            log::warn!(
+                "receipt {} is corrupt, prove segment {} again",
+                path,
+                task.file_no
+            );
+            if task.state == TASK_STATE_SUCCESS {
+                task.state = TASK_STATE_UNPROCESSED;
+                task.retry_at = 0;
+            }
+            let task_id = task.task_id.clone();
+            if matches!(self.step, Step::InAgg | Step::InAggAll | Step::InWrap) {
+                self.step = Step::InProve;
+            }
+            return Some(task_id);
+        }
+        if self.agg_all_task.agg_receipt_path == path {
+            log::warn!("receipt {} is corrupt, agg_all again", path);
+            if self.agg_all_task.state == TASK_STATE_SUCCESS {
+                self.agg_all_task.state = TASK_STATE_UNPROCESSED;
+                self.agg_all_task.retry_at = 0;
+            }
+            self.step = Step::InAggAll;
+            return Some(self.agg_all_task.task_id.clone());
+        }
+        let task = self
+            .agg_tasks
+            .iter_mut()
+            .find(|task| task.output_receipt_path == path)?;
+        log::warn!("receipt {} is corrupt, agg {} again", path, task.file_key);
+        if task.state == TASK_STATE_SUCCESS {
+            task.state = TASK_STATE_UNPROCESSED;
+            task.retry_at = 0;
+        }
+        let task_id = task.task_id.clone();
+        if self.step == Step::InWrap {
+            self.step = Step::InAgg;
+        }
+        Some(task_id)
+    }
+
+    fn clear_agg_dependency(&mut self, task_id: &str) {
+        for item_task in &mut self.agg_tasks {
+            if item_task.clear_child_task(task_id) {
+                break;
+            }
+        }
+    }
+
+    /// Point the aggregations at the receipt written by a speculative copy
+    fn move_receipt(&mut self, old_path: &str, new_path: &str) {
+        for item_task in &mut self.agg_tasks {
+            if item_task.from_prove && item_task.output_receipt_path == old_path {
+                item_task.output_receipt_path = new_path.to_string();
+            }
+            for input in [&mut item_task.input1, &mut item_task.input2] {
+                if input.receipt_path == old_path {
+                    input.receipt_path = new_path.to_string();
                 }
             }
         }
@@ -368,6 +991,8 @@ impl Stage {
         self.agg_all_task
             .output_dir
             .clone_from(&self.generate_context.agg_path);
+        self.agg_all_task.agg_receipt_path =
+            format!("{}/receipt/agg_all", self.generate_context.prove_path);
         log::debug!("gen_agg_task {:#?}", self.agg_all_task);
     }
 
@@ -377,8 +1002,72 @@ impl Stage {
     }
 
     pub fn on_agg_all_task(&mut self, agg_all_task: &mut AggAllTask) {
+        let attempts = self.agg_all_task.attempts + 1;
+        if self.agg_all_task.state == TASK_STATE_PROCESSING
+            && attempts < self.retry_policies.agg_all.max_attempts
+            && self
+                .regenerate_input(agg_all_task.state, &agg_all_task.errmsg)
+                .is_some()
+        {
+            // generated again once the root proofs are done
+            self.agg_all_task.state = TASK_STATE_INITIAL;
+            self.agg_all_task.attempts = attempts;
+            self.agg_all_task.errmsg.clone_from(&agg_all_task.errmsg);
+            agg_all_task.state = self.agg_all_task.state;
+            agg_all_task.attempts = attempts;
+            return;
+        }
         let dst = &mut self.agg_all_task;
-        on_task!(agg_all_task, dst, self);
+        on_task!(agg_all_task, dst, self, "agg_all", agg_all);
+    }
+
+    /// Wrap the receipt of the last aggregation, in a task of its own so that a failed
+    /// wrap does not redo the aggregation
+    fn gen_wrap_task(&mut self) {
+        assert!(self.wrap_task.state == TASK_STATE_INITIAL);
+        self.wrap_task.agg_receipt_path = match self.agg_tasks.iter().find(|task| task.is_final) {
+            Some(agg_task) => agg_task.output_receipt_path.clone(),
+            None => self.agg_all_task.agg_receipt_path.clone(),
+        };
+        self.wrap_task.task_id = uuid::Uuid::new_v4().to_string();
+        self.wrap_task.state = TASK_STATE_UNPROCESSED;
+        self.wrap_task
+            .base_dir
+            .clone_from(&self.generate_context.basedir);
+        self.wrap_task.block_no = self.generate_context.block_no;
+        self.wrap_task.seg_size = self.generate_context.seg_size;
+        self.wrap_task
+            .proof_id
+            .clone_from(&self.generate_context.proof_id);
+        self.wrap_task
+            .output_dir
+            .clone_from(&self.generate_context.agg_path);
+        log::debug!("gen_wrap_task {:#?}", self.wrap_task);
+    }
+
+    pub fn get_wrap_task(&mut self) -> Option<WrapTask> {
+        let src = &mut self.wrap_task;
+        get_task!(src);
+    }
+
+    pub fn on_wrap_task(&mut self, wrap_task: &mut WrapTask) {
+        let attempts = self.wrap_task.attempts + 1;
+        if self.wrap_task.state == TASK_STATE_PROCESSING
+            && attempts < self.retry_policies.wrap.max_attempts
+            && self
+                .regenerate_input(wrap_task.state, &wrap_task.errmsg)
+                .is_some()
+        {
+            // generated again once the aggregation is done
+            self.wrap_task.state = TASK_STATE_INITIAL;
+            self.wrap_task.attempts = attempts;
+            self.wrap_task.errmsg.clone_from(&wrap_task.errmsg);
+            wrap_task.state = self.wrap_task.state;
+            wrap_task.attempts = attempts;
+            return;
+        }
+        let dst = &mut self.wrap_task;
+        on_task!(wrap_task, dst, self, "wrap", wrap);
     }
 
     pub fn gen_final_task(&mut self) {
@@ -404,7 +1093,36 @@ impl Stage {
 
     pub fn on_final_task(&mut self, final_task: &mut FinalTask) {
         let dst = &mut self.final_task;
-        on_task!(final_task, dst, self);
+        on_task!(final_task, dst, self, "final", final_proof);
+    }
+
+    /// Return (finished, total) root prove tasks
+    pub fn prove_progress(&self) -> (u32, u32) {
+        let done = self
+            .prove_tasks
+            .iter()
+            .filter(|task| task.state == TASK_STATE_SUCCESS)
+            .count();
+        (done as u32, self.prove_tasks.len() as u32)
+    }
+
+    /// Return (finished, total) aggregation tasks, including the agg_all task
+    pub fn agg_progress(&self) -> (u32, u32) {
+        let mut done = 0;
+        let mut total = 0;
+        for task in self.agg_tasks.iter().filter(|task| !task.from_prove) {
+            total += 1;
+            if task.state == TASK_STATE_SUCCESS {
+                done += 1;
+            }
+        }
+        if self.agg_all_task.state != TASK_STATE_INITIAL {
+            total += 1;
+            if self.agg_all_task.state == TASK_STATE_SUCCESS {
+                done += 1;
+            }
+        }
+        (done, total)
     }
 
     pub fn timecost_string(&self) -> String {
@@ -442,23 +1160,40 @@ impl Stage {
             self.agg_all_task.task_id,
             self.agg_all_task.finish_ts - self.agg_all_task.start_ts
         );
+        let wrap_cost = format!(
+            "wrap_id: {} cost: {} sec",
+            self.wrap_task.task_id,
+            self.wrap_task.finish_ts - self.wrap_task.start_ts
+        );
         let final_cost = format!(
             "final_id: {} cost: {} sec",
             self.final_task.task_id,
             self.final_task.finish_ts - self.final_task.start_ts
         );
         format!(
-            "proof_id: {}\r\n {}\r\n {}\r\n {}\r\n {}\r\n {}\r\n",
+            "proof_id: {}\r\n {}\r\n {}\r\n {}\r\n {}\r\n {}\r\n {}\r\n",
             self.generate_context.proof_id,
             split_cost,
             root_prove_cost,
             agg_cost,
             agg_all_cost,
+            wrap_cost,
             final_cost
         )
     }
 }
 
+/// Running time after which a task counts as a straggler, None until enough siblings finished
+fn straggler_threshold(time_costs: impl Iterator<Item = u64>) -> Option<u64> {
+    let mut time_costs: Vec<u64> = time_costs.collect();
+    if time_costs.len() < STRAGGLER_MIN_FINISHED {
+        return None;
+    }
+    time_costs.sort_unstable();
+    let median = time_costs[time_costs.len() / 2];
+    Some((median * STRAGGLER_FACTOR).max(STRAGGLER_MIN_SECS))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -487,4 +1222,732 @@ mod tests {
             assert!(stage.agg_tasks.len() <= n);
         }
     }
+
+    #[test]
+    fn test_agg_strategy() {
+        let new_stage = |agg_strategy: AggStrategy, n: usize| {
+            let mut stage = Stage::new(GenerateContext {
+                agg_strategy,
+                ..Default::default()
+            });
+            for i in 0..n {
+                stage.prove_tasks.push(ProveTask {
+                    file_no: i,
+                    task_id: format!("prove_{}", i),
+                    state: TASK_STATE_SUCCESS,
+                    ..Default::default()
+                })
+            }
+            stage.gen_agg_tasks();
+            stage
+        };
+        let stage = new_stage(
+            AggStrategy {
+                shape: AggTreeShape::LeftDeep,
+                ..Default::default()
+            },
+            5,
+        );
+        assert_eq!(stage.agg_tasks.len(), 4);
+        for i in 1..4 {
+            assert_eq!(
+                stage.agg_tasks[i].left.as_ref(),
+                Some(&stage.agg_tasks[i - 1].task_id)
+            );
+        }
+        assert!(stage.agg_tasks[3].is_final);
+        // only the first aggregation can start
+        let mut stage = stage;
+        assert!(stage.get_agg_task().is_some());
+        assert!(stage.get_agg_task().is_none());
+
+        let mut stage = new_stage(
+            AggStrategy {
+                max_width: 2,
+                ..Default::default()
+            },
+            8,
+        );
+        assert_eq!(stage.agg_tasks.len(), 7);
+        assert!(stage.get_agg_task().is_some());
+        assert!(stage.get_agg_task().is_some());
+        assert!(stage.get_agg_task().is_none());
+
+        let mut stage = new_stage(AggStrategy::default(), 8);
+        assert_eq!(stage.agg_tasks.len(), 7);
+        for _ in 0..4 {
+            assert!(stage.get_agg_task().is_some());
+        }
+        assert!(stage.get_agg_task().is_none());
+    }
+
+    #[test]
+    fn test_agg_before_prove_done() {
+        let mut stage = Stage::default();
+        for i in 0..5 {
+            stage.prove_tasks.push(ProveTask {
+                file_no: i,
+                task_id: format!("prove_{}", i),
+                proof_id: "proof".to_string(),
+                state: TASK_STATE_PROCESSING,
+                ..Default::default()
+            })
+        }
+        stage.gen_agg_tasks();
+        assert!(stage.get_agg_task().is_none());
+
+        for i in [1, 0] {
+            let mut result = stage.prove_tasks[i].clone();
+            result.state = TASK_STATE_SUCCESS;
+            stage.on_prove_task(&mut result);
+        }
+        let agg_task = stage.get_agg_task().unwrap();
+        assert_eq!(agg_task.left, None);
+        assert_eq!(
+            agg_task.input1.receipt_path,
+            stage.prove_tasks[0].receipt_path
+        );
+        assert!(stage.get_agg_task().is_none());
+
+        // the odd segment is passed up the tree, its parent waits for the root proof
+        let mut result = stage.prove_tasks[4].clone();
+        result.state = TASK_STATE_SUCCESS;
+        stage.on_prove_task(&mut result);
+        assert!(stage
+            .agg_tasks
+            .iter()
+            .all(|task| task.left.as_deref() != Some("prove_4")
+                && task.right.as_deref() != Some("prove_4")));
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut stage = Stage::new(GenerateContext::default());
+        stage.dispatch();
+        assert!(stage.step == Step::InSplit);
+        stage.split_task.state = TASK_STATE_SUCCESS;
+        stage.cancel();
+        stage.dispatch();
+        assert!(stage.is_cancelled());
+        assert!(stage.step == Step::InSplit);
+    }
+
+    #[test]
+    fn test_prove_task_error() {
+        let mut stage = Stage::default();
+        stage.retry_policies.prove.max_attempts = 1;
+        stage.prove_tasks.push(ProveTask {
+            file_no: 7,
+            task_id: "prove_7".to_string(),
+            state: TASK_STATE_PROCESSING,
+            ..Default::default()
+        });
+        let mut result = stage.prove_tasks[0].clone();
+        result.state = TASK_STATE_FAILED;
+        result.node_info = "127.0.0.1:50000".to_string();
+        result.errmsg = "out of memory".to_string();
+        stage.on_prove_task(&mut result);
+        assert!(stage.is_error());
+        assert_eq!(stage.prove_tasks[0].errmsg, "out of memory");
+        assert_eq!(
+            stage.errmsg,
+            "prove segment 7 task prove_7 failed on 127.0.0.1:50000: out of memory"
+        );
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let mut stage = Stage::default();
+        stage.retry_policies.prove = RetryPolicy {
+            max_attempts: 2,
+            backoff_secs: 0,
+            ..Default::default()
+        };
+        stage.prove_tasks.push(ProveTask {
+            file_no: 3,
+            task_id: "prove_3".to_string(),
+            state: TASK_STATE_UNPROCESSED,
+            ..Default::default()
+        });
+        let mut result = stage.get_prove_task().unwrap();
+        result.state = TASK_STATE_FAILED;
+        result.node_info = "127.0.0.1:50000".to_string();
+        stage.on_prove_task(&mut result);
+        assert!(!stage.is_error());
+        assert_eq!(result.state, TASK_STATE_UNPROCESSED);
+        assert_eq!(stage.prove_tasks[0].attempts, 1);
+        assert_eq!(stage.prove_tasks[0].excluded_nodes, vec!["127.0.0.1:50000"]);
+
+        let mut result = stage.get_prove_task().unwrap();
+        result.state = TASK_STATE_FAILED;
+        result.node_info = "127.0.0.1:50001".to_string();
+        stage.on_prove_task(&mut result);
+        assert!(stage.is_error());
+        assert_eq!(stage.prove_tasks[0].state, TASK_STATE_FAILED);
+        assert_eq!(stage.prove_tasks[0].attempts, 2);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            backoff_secs: 5,
+            max_backoff_secs: 60,
+            exclude_failed_node: false,
+        };
+        assert_eq!(policy.backoff(1), 5);
+        assert_eq!(policy.backoff(2), 10);
+        assert_eq!(policy.backoff(4), 40);
+        assert_eq!(policy.backoff(5), 60);
+        assert_eq!(policy.backoff(100), 60);
+
+        let mut stage = Stage::default();
+        stage.prove_tasks.push(ProveTask {
+            task_id: "prove_0".to_string(),
+            state: TASK_STATE_UNPROCESSED,
+            ..Default::default()
+        });
+        let mut result = stage.get_prove_task().unwrap();
+        // BUSY answer from a node
+        result.state = TASK_STATE_UNPROCESSED;
+        result.node_info = "127.0.0.1:50000".to_string();
+        stage.on_prove_task(&mut result);
+        assert_eq!(stage.prove_tasks[0].attempts, 0);
+        assert!(stage.prove_tasks[0].excluded_nodes.is_empty());
+        assert!(stage.get_prove_task().is_none());
+    }
+
+    #[test]
+    fn test_busy_answers() {
+        let mut stage = Stage::default();
+        stage.retry_policies.prove.max_attempts = 3;
+        stage.prove_tasks.push(ProveTask {
+            task_id: "prove_0".to_string(),
+            state: TASK_STATE_UNPROCESSED,
+            ..Default::default()
+        });
+        for _ in 0..10 {
+            stage.prove_tasks[0].retry_at = 0;
+            let mut result = stage.get_prove_task().unwrap();
+            // a draining node answers BUSY
+            result.state = TASK_STATE_UNPROCESSED;
+            result.node_info = "127.0.0.1:50000".to_string();
+            stage.on_prove_task(&mut result);
+            assert!(!stage.is_error());
+            assert_eq!(stage.prove_tasks[0].state, TASK_STATE_UNPROCESSED);
+            assert_eq!(stage.prove_tasks[0].attempts, 0);
+        }
+    }
+
+    #[test]
+    fn test_speculative_prove_task() {
+        let mut stage = Stage::default();
+        let now = get_timestamp();
+        for i in 0..5 {
+            stage.prove_tasks.push(ProveTask {
+                file_no: i,
+                task_id: format!("prove_{}", i),
+                receipt_path: format!("receipt/{}", i),
+                state: TASK_STATE_SUCCESS,
+                start_ts: now - 1000,
+                finish_ts: now - 970,
+                ..Default::default()
+            })
+        }
+        stage.prove_tasks[4].state = TASK_STATE_PROCESSING;
+        stage.prove_tasks[4].start_ts = now - 100;
+        // still waiting for a node
+        assert!(stage.get_prove_copy(|_| true).is_none());
+
+        let mut running = RunningTask {
+            itype: TASK_ITYPE_PROVE,
+            task_id: "prove_4".to_string(),
+            node_info: "127.0.0.1:50000".to_string(),
+            start_ts: now - 30,
+            speculative: false,
+        };
+        stage.on_task_running(&running);
+        assert!(stage.get_prove_copy(|_| true).is_none());
+
+        stage.prove_tasks[4].start_ts = now - 100;
+        let copy = stage.get_prove_copy(|_| true).unwrap();
+        assert_eq!(copy.task_id, "prove_4-spec");
+        assert!(copy.speculative);
+        assert_eq!(copy.receipt_path, "receipt/4.speculative");
+        assert_eq!(copy.excluded_nodes, vec!["127.0.0.1:50000".to_string()]);
+        assert!(stage.get_prove_copy(|_| true).is_none());
+
+        running.task_id = copy.task_id.clone();
+        running.node_info = "127.0.0.1:50001".to_string();
+        running.start_ts = now;
+        running.speculative = true;
+        stage.on_task_running(&running);
+        assert_eq!(stage.prove_tasks[4].node_info, "127.0.0.1:50000");
+        assert_eq!(stage.prove_tasks[4].start_ts, now - 100);
+
+        let mut late = stage.prove_tasks[4].clone();
+        let mut result = copy;
+        result.state = TASK_STATE_SUCCESS;
+        stage.on_prove_task(&mut result);
+        assert_eq!(stage.prove_tasks[4].state, TASK_STATE_SUCCESS);
+        assert_eq!(stage.prove_tasks[4].receipt_path, "receipt/4.speculative");
+
+        late.state = TASK_STATE_FAILED;
+        stage.on_prove_task(&mut late);
+        assert!(!stage.is_error());
+        assert_eq!(stage.prove_tasks[4].state, TASK_STATE_SUCCESS);
+    }
+
+    #[test]
+    fn test_speculative_single_node() {
+        let mut stage = Stage::default();
+        let now = get_timestamp();
+        for i in 0..5 {
+            stage.prove_tasks.push(ProveTask {
+                file_no: i,
+                task_id: format!("prove_{}", i),
+                receipt_path: format!("receipt/{}", i),
+                state: TASK_STATE_SUCCESS,
+                start_ts: now - 1000,
+                finish_ts: now - 970,
+                node_info: "127.0.0.1:50000".to_string(),
+                ..Default::default()
+            })
+        }
+        stage.prove_tasks[4].state = TASK_STATE_PROCESSING;
+        stage.prove_tasks[4].start_ts = now - 100;
+        // the only node of the cluster runs the straggler, a copy would end up on it again
+        let nodes = ["127.0.0.1:50000".to_string()];
+        let spare_node = |excluded: &[String]| nodes.iter().any(|node| !excluded.contains(node));
+        assert!(stage.get_prove_copy(spare_node).is_none());
+        assert!(!stage.prove_tasks[4].speculative);
+        assert_eq!(stage.prove_tasks[4].receipt_path, "receipt/4");
+
+        // the straggler finishing on its own is not taken for a copy
+        let mut result = stage.prove_tasks[4].clone();
+        result.state = TASK_STATE_SUCCESS;
+        stage.on_prove_task(&mut result);
+        assert_eq!(stage.prove_tasks[4].state, TASK_STATE_SUCCESS);
+        assert_eq!(stage.prove_tasks[4].receipt_path, "receipt/4");
+    }
+
+    #[test]
+    fn test_speculative_copy_failed() {
+        let mut stage = Stage::default();
+        stage.prove_tasks.push(ProveTask {
+            task_id: "prove_0".to_string(),
+            state: TASK_STATE_PROCESSING,
+            speculative: true,
+            ..Default::default()
+        });
+        let mut result = stage.prove_tasks[0].clone();
+        result.task_id = format!("prove_0{}", SPECULATIVE_SUFFIX);
+        result.state = TASK_STATE_FAILED;
+        stage.on_prove_task(&mut result);
+        assert_eq!(result.state, TASK_STATE_PROCESSING);
+        assert_eq!(stage.prove_tasks[0].state, TASK_STATE_PROCESSING);
+        assert_eq!(stage.prove_tasks[0].attempts, 0);
+        assert!(!stage.prove_tasks[0].speculative);
+    }
+
+    #[test]
+    fn test_speculative_agg_task() {
+        let mut stage = Stage::default();
+        for i in 0..8 {
+            stage.prove_tasks.push(ProveTask {
+                file_no: i,
+                task_id: format!("prove_{}", i),
+                state: TASK_STATE_SUCCESS,
+                ..Default::default()
+            })
+        }
+        stage.gen_agg_tasks();
+        let now = get_timestamp();
+        let mut running = None;
+        for (i, task) in stage.agg_tasks.iter_mut().enumerate() {
+            if task.from_prove || task.left.is_some() || task.right.is_some() {
+                continue;
+            }
+            if running.is_none() {
+                task.state = TASK_STATE_PROCESSING;
+                task.start_ts = now - 200;
+                task.node_info = "127.0.0.1:50000".to_string();
+                running = Some(i);
+            } else {
+                task.state = TASK_STATE_SUCCESS;
+                task.start_ts = now - 1000;
+                task.finish_ts = now - 990;
+            }
+        }
+        let running = running.unwrap();
+        assert!(stage.get_agg_task().is_none());
+        let copy = stage.get_agg_copy(|_| true).unwrap();
+        assert_eq!(
+            copy.task_id,
+            format!("{}-spec", stage.agg_tasks[running].task_id)
+        );
+        assert_eq!(copy.excluded_nodes, vec!["127.0.0.1:50000".to_string()]);
+        let old_path = stage.agg_tasks[running].output_receipt_path.clone();
+        let mut result = copy;
+        result.state = TASK_STATE_SUCCESS;
+        stage.on_agg_task(&mut result);
+        assert_eq!(
+            stage.agg_tasks[running].output_receipt_path,
+            format!("{}.speculative", old_path)
+        );
+        let parent = stage
+            .agg_tasks
+            .iter()
+            .find(|task| {
+                task.input1.receipt_path == result.output_receipt_path
+                    || task.input2.receipt_path == result.output_receipt_path
+            })
+            .unwrap();
+        assert!(parent.left.as_ref() != Some(&result.task_id));
+        assert!(parent.right.as_ref() != Some(&result.task_id));
+    }
+
+    #[test]
+    fn test_split_prove_tasks() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let seg_path = dir.join("segments");
+        std::fs::create_dir_all(&seg_path).unwrap();
+        let mut stage = Stage::new(GenerateContext {
+            seg_path: seg_path.to_str().unwrap().to_string(),
+            prove_path: dir.join("prove").to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        stage.dispatch();
+        assert!(stage.step == Step::InSplit);
+        stage.dispatch();
+        assert!(stage.prove_tasks.is_empty());
+
+        for file_no in 0..2 {
+            std::fs::write(seg_path.join(file_no.to_string()), "").unwrap();
+        }
+        std::fs::write(seg_path.join(file::SEGMENT_MANIFEST), "2").unwrap();
+        stage.dispatch();
+        assert_eq!(stage.prove_tasks.len(), 2);
+        assert_eq!(stage.get_prove_task().unwrap().file_no, 0);
+
+        std::fs::write(seg_path.join("2"), "").unwrap();
+        std::fs::write(seg_path.join(file::SEGMENT_MANIFEST), "3").unwrap();
+        stage.split_task.state = TASK_STATE_SUCCESS;
+        stage.dispatch();
+        assert!(stage.step == Step::InProve);
+        assert_eq!(
+            stage
+                .prove_tasks
+                .iter()
+                .map(|task| task.file_no)
+                .collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(stage.prove_tasks[0].state, TASK_STATE_PROCESSING);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_segment() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let seg_path = dir.join("segments");
+        std::fs::create_dir_all(&seg_path).unwrap();
+        std::fs::write(seg_path.join("0"), "").unwrap();
+        let new_stage = |precompile: bool, total_steps: u64| {
+            let mut stage = Stage::new(GenerateContext {
+                seg_path: seg_path.to_str().unwrap().to_string(),
+                prove_path: dir.join("prove").to_str().unwrap().to_string(),
+                agg_path: dir.join("agg").to_str().unwrap().to_string(),
+                seg_size: 1 << 20,
+                precompile,
+                ..Default::default()
+            });
+            stage.dispatch();
+            stage.split_task.state = TASK_STATE_SUCCESS;
+            stage.split_task.total_steps = total_steps;
+            stage
+        };
+
+        let mut stage = new_stage(false, 100000);
+        let split_id = stage.split_task.task_id.clone();
+        stage.dispatch();
+        assert!(!stage.is_error());
+        assert!(stage.step == Step::InSplit);
+        assert!(stage.prove_tasks.is_empty());
+        // only the split cuts smaller segments, they are proved with the same circuits
+        assert_eq!(stage.generate_context.seg_size, 1 << 20);
+        assert_eq!(stage.generate_context.split_seg_size, 131072);
+        let split_task = stage.get_split_task().unwrap();
+        assert_ne!(split_task.task_id, split_id);
+        assert_eq!(split_task.seg_size, 131072);
+        assert!(split_task.seg_path.ends_with("resplit_131072"));
+
+        // far below the smallest segment size
+        let mut stage = new_stage(false, 1000);
+        stage.dispatch();
+        assert!(!stage.is_error());
+        assert!(stage.step == Step::InSplit);
+        assert_eq!(stage.split_task.seg_size, 1024);
+        // still one segment, split again with less
+        stage.split_task.state = TASK_STATE_SUCCESS;
+        stage.split_task.total_steps = 300;
+        std::fs::create_dir_all(&stage.generate_context.seg_path).unwrap();
+        std::fs::write(format!("{}/0", stage.generate_context.seg_path), "").unwrap();
+        stage.dispatch();
+        assert!(!stage.is_error());
+        assert_eq!(stage.split_task.seg_size, 512);
+
+        // two segments are aggregated, a lone root receipt is never wrapped
+        stage.split_task.state = TASK_STATE_SUCCESS;
+        std::fs::create_dir_all(&stage.generate_context.seg_path).unwrap();
+        for file_no in 0..2 {
+            std::fs::write(
+                format!("{}/{}", stage.generate_context.seg_path, file_no),
+                "",
+            )
+            .unwrap();
+        }
+        stage.dispatch();
+        assert!(stage.step == Step::InProve);
+        assert_eq!(stage.prove_tasks.len(), 2);
+        for task in &mut stage.prove_tasks {
+            assert_eq!(task.seg_size, 1 << 20);
+            task.state = TASK_STATE_SUCCESS;
+        }
+        stage.dispatch();
+        assert!(stage.step == Step::InAggAll);
+
+        // too short to split
+        let mut stage = new_stage(false, 1);
+        stage.dispatch();
+        assert!(stage.is_error());
+
+        // root receipts of a precompile are not aggregated
+        let mut stage = new_stage(true, 1000);
+        stage.dispatch();
+        assert!(stage.step == Step::InProve);
+        assert_eq!(stage.prove_tasks.len(), 1);
+
+        std::fs::remove_file(seg_path.join("0")).unwrap();
+        let mut stage = new_stage(false, 0);
+        stage.dispatch();
+        assert!(stage.is_error());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut stage = Stage::default();
+        for (i, state) in [
+            TASK_STATE_SUCCESS,
+            TASK_STATE_PROCESSING,
+            TASK_STATE_UNPROCESSED,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            stage.prove_tasks.push(ProveTask {
+                file_no: i,
+                state,
+                ..Default::default()
+            })
+        }
+        stage.step = Step::InProve;
+        stage.resume();
+        assert!(stage.step == Step::InProve);
+        assert_eq!(stage.prove_tasks[0].state, TASK_STATE_SUCCESS);
+        assert_eq!(stage.prove_tasks[1].state, TASK_STATE_UNPROCESSED);
+        assert_eq!(stage.get_prove_task().unwrap().file_no, 1);
+    }
+
+    #[test]
+    fn test_resume_failed() {
+        let mut stage = Stage::default();
+        stage.retry_policies.prove.max_attempts = 1;
+        for i in 0..2 {
+            stage.prove_tasks.push(ProveTask {
+                file_no: i,
+                task_id: i.to_string(),
+                state: TASK_STATE_PROCESSING,
+                ..Default::default()
+            })
+        }
+        stage.step = Step::InProve;
+        let mut result = stage.prove_tasks[1].clone();
+        result.state = TASK_STATE_FAILED;
+        stage.on_prove_task(&mut result);
+        assert!(stage.is_error());
+        stage.prove_tasks[0].state = TASK_STATE_SUCCESS;
+        stage.resume();
+        assert!(!stage.is_error());
+        assert!(stage.errmsg.is_empty());
+        assert_eq!(stage.prove_tasks[0].state, TASK_STATE_SUCCESS);
+        assert_eq!(stage.get_prove_task().unwrap().file_no, 1);
+    }
+
+    #[test]
+    fn test_corrupt_receipt() {
+        let mut stage = Stage::default();
+        for i in 0..5 {
+            stage.prove_tasks.push(ProveTask {
+                file_no: i,
+                task_id: format!("prove_{}", i),
+                receipt_path: format!("receipt/{}", i),
+                state: TASK_STATE_SUCCESS,
+                ..Default::default()
+            })
+        }
+        stage.gen_agg_tasks();
+        stage.step = Step::InAgg;
+        let mut agg_task = stage.get_agg_task().unwrap();
+        agg_task.state = TASK_STATE_FAILED;
+        agg_task.errmsg = format!("{} receipt/1", file::CORRUPT_ARTIFACT);
+        stage.on_agg_task(&mut agg_task);
+        assert!(!stage.is_error());
+        assert_eq!(agg_task.state, TASK_STATE_UNPROCESSED);
+        assert_eq!(stage.prove_tasks[1].state, TASK_STATE_UNPROCESSED);
+        assert!(stage.step == Step::InProve);
+        // the aggregation waits for the root proof to be done again
+        assert!(stage
+            .agg_tasks
+            .iter()
+            .all(|task| task.task_id != agg_task.task_id || task.right.is_some()));
+
+        let mut prove_task = stage.get_prove_task().unwrap();
+        assert_eq!(prove_task.file_no, 1);
+        prove_task.state = TASK_STATE_SUCCESS;
+        stage.on_prove_task(&mut prove_task);
+        let retried = stage
+            .agg_tasks
+            .iter()
+            .find(|task| task.task_id == agg_task.task_id)
+            .unwrap();
+        assert_eq!(retried.right, None);
+        assert_eq!(retried.attempts, 1);
+    }
+
+    #[test]
+    fn test_corrupt_segment() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let seg_path = dir.join("segments");
+        std::fs::create_dir_all(&seg_path).unwrap();
+        for file_no in 0..2 {
+            std::fs::write(seg_path.join(file_no.to_string()), "").unwrap();
+        }
+        let mut stage = Stage::new(GenerateContext {
+            seg_path: seg_path.to_str().unwrap().to_string(),
+            prove_path: dir.join("prove").to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        stage.dispatch();
+        stage.split_task.state = TASK_STATE_SUCCESS;
+        stage.dispatch();
+        assert!(stage.step == Step::InProve);
+        let split_id = stage.split_task.task_id.clone();
+
+        let mut prove_task = stage.get_prove_task().unwrap();
+        prove_task.state = TASK_STATE_FAILED;
+        prove_task.errmsg = format!("{} {}", file::CORRUPT_ARTIFACT, prove_task.seg_path);
+        stage.on_prove_task(&mut prove_task);
+        assert!(!stage.is_error());
+        assert!(stage.step == Step::InSplit);
+        assert_eq!(stage.prove_tasks[0].state, TASK_STATE_INITIAL);
+        let split_task = stage.get_split_task().unwrap();
+        assert_ne!(split_task.task_id, split_id);
+        assert_eq!(stage.get_prove_task().unwrap().file_no, 1);
+        assert!(stage.get_prove_task().is_none());
+
+        stage.split_task.state = TASK_STATE_SUCCESS;
+        stage.dispatch();
+        assert!(stage.step == Step::InProve);
+        assert_eq!(stage.prove_tasks.len(), 2);
+        let prove_task = stage.get_prove_task().unwrap();
+        assert_eq!(prove_task.file_no, 0);
+        assert_eq!(prove_task.attempts, 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_wrap_task() {
+        // clients and stored proofs compare against the step values
+        assert_eq!(i32::from(Step::InFinal), 5);
+        assert_eq!(i32::from(Step::End), 6);
+        assert_eq!(i32::from(Step::InWrap), 7);
+
+        let mut stage = Stage::new(GenerateContext {
+            prove_path: "prove".to_string(),
+            agg_path: "aggregate".to_string(),
+            ..Default::default()
+        });
+        stage.step = Step::InProve;
+        for i in 0..2 {
+            stage.prove_tasks.push(ProveTask {
+                file_no: i,
+                task_id: format!("prove_{}", i),
+                state: TASK_STATE_SUCCESS,
+                ..Default::default()
+            })
+        }
+        stage.dispatch();
+        assert!(stage.step == Step::InAggAll);
+        let mut agg_all_task = stage.get_agg_all_task().unwrap();
+        assert_eq!(agg_all_task.agg_receipt_path, "prove/receipt/agg_all");
+        agg_all_task.state = TASK_STATE_SUCCESS;
+        stage.on_agg_all_task(&mut agg_all_task);
+        stage.dispatch();
+        assert!(stage.step == Step::InWrap);
+
+        // a failed wrap is retried on its own
+        let mut wrap_task = stage.get_wrap_task().unwrap();
+        assert_eq!(wrap_task.agg_receipt_path, agg_all_task.agg_receipt_path);
+        assert_eq!(wrap_task.output_dir, "aggregate");
+        wrap_task.state = TASK_STATE_FAILED;
+        wrap_task.node_info = "127.0.0.1:50000".to_string();
+        stage.on_wrap_task(&mut wrap_task);
+        assert!(!stage.is_error());
+        assert_eq!(stage.agg_all_task.state, TASK_STATE_SUCCESS);
+        stage.wrap_task.retry_at = 0;
+        let mut wrap_task = stage.get_wrap_task().unwrap();
+        assert_eq!(
+            wrap_task.excluded_nodes,
+            vec!["127.0.0.1:50000".to_string()]
+        );
+
+        // a corrupt receipt is aggregated again before the next wrap
+        wrap_task.state = TASK_STATE_FAILED;
+        wrap_task.errmsg = format!("{} prove/receipt/agg_all", file::CORRUPT_ARTIFACT);
+        stage.on_wrap_task(&mut wrap_task);
+        assert!(stage.step == Step::InAggAll);
+        assert_eq!(stage.wrap_task.state, TASK_STATE_INITIAL);
+        let mut agg_all_task = stage.get_agg_all_task().unwrap();
+        agg_all_task.state = TASK_STATE_SUCCESS;
+        stage.on_agg_all_task(&mut agg_all_task);
+        stage.dispatch();
+        assert!(stage.step == Step::InWrap);
+        let mut wrap_task = stage.get_wrap_task().unwrap();
+        assert_eq!(wrap_task.attempts, 2);
+
+        wrap_task.state = TASK_STATE_SUCCESS;
+        stage.on_wrap_task(&mut wrap_task);
+        stage.dispatch();
+        assert!(stage.step == Step::InFinal);
+        assert_eq!(stage.final_task.input_dir, "aggregate");
+    }
+
+    #[test]
+    fn test_progress() {
+        let mut stage = Stage::default();
+        for i in 0..5 {
+            stage.prove_tasks.push(ProveTask {
+                file_no: i,
+                state: TASK_STATE_SUCCESS,
+                ..Default::default()
+            })
+        }
+        stage.prove_tasks[4].state = TASK_STATE_PROCESSING;
+        assert_eq!(stage.prove_progress(), (4, 5));
+        stage.gen_agg_tasks();
+        // the odd segment is carried over without an aggregation
+        assert_eq!(stage.agg_progress(), (0, 4));
+    }
 }
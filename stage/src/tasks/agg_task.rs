@@ -14,9 +14,20 @@ pub struct AggAllTask {
     pub proof_id: String,
     pub receipt_dir: String,
     pub output_dir: String,
+    // the aggregate receipt, wrapped by the wrap task
+    #[serde(default)]
+    pub agg_receipt_path: String,
     pub start_ts: u64,
     pub finish_ts: u64,
     pub node_info: String,
+    #[serde(default)]
+    pub errmsg: String,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub retry_at: u64,
+    #[serde(default)]
+    pub excluded_nodes: Vec<String>,
 }
 
 impl Clone for AggAllTask {
@@ -31,9 +42,14 @@ impl Clone for AggAllTask {
             proof_num: self.proof_num,
             receipt_dir: self.receipt_dir.clone(),
             output_dir: self.output_dir.clone(),
+            agg_receipt_path: self.agg_receipt_path.clone(),
             start_ts: self.start_ts,
             finish_ts: self.finish_ts,
             node_info: self.node_info.clone(),
+            errmsg: self.errmsg.clone(),
+            attempts: self.attempts,
+            retry_at: self.retry_at,
+            excluded_nodes: self.excluded_nodes.clone(),
         }
     }
 }
@@ -71,6 +87,17 @@ pub struct AggTask {
     pub start_ts: u64,
     pub finish_ts: u64,
     pub node_info: String,
+    #[serde(default)]
+    pub errmsg: String,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub retry_at: u64,
+    #[serde(default)]
+    pub excluded_nodes: Vec<String>,
+    // a duplicate of the task is running on another node
+    #[serde(default)]
+    pub speculative: bool,
 
     // depend
     pub left: Option<String>,
@@ -96,6 +123,11 @@ impl Clone for AggTask {
             start_ts: self.start_ts,
             finish_ts: self.finish_ts,
             node_info: self.node_info.clone(),
+            errmsg: self.errmsg.clone(),
+            attempts: self.attempts,
+            retry_at: self.retry_at,
+            excluded_nodes: self.excluded_nodes.clone(),
+            speculative: self.speculative,
             left: self.left.clone(),
             right: self.right.clone(),
         }
@@ -132,20 +164,21 @@ impl AggTask {
         }
     }
 
-    pub fn init_from_single_prove_task(prove_task: &ProveTask, prove_dir: &str) -> AggTask {
-        let mut agg_task = AggTask {
+    pub fn init_from_single_prove_task(prove_task: &ProveTask) -> AggTask {
+        AggTask {
             file_key: format!("{}", prove_task.file_no),
-            task_id: uuid::Uuid::new_v4().to_string(),
+            // same id as the prove task, so the aggregation above waits for the root proof
+            task_id: prove_task.task_id.clone(),
             base_dir: prove_task.base_dir.clone(),
             block_no: prove_task.block_no,
             state: TASK_STATE_SUCCESS,
             seg_size: prove_task.seg_size,
             proof_id: prove_task.proof_id.clone(),
             from_prove: true,
+            // a speculative copy may have written the receipt elsewhere
+            output_receipt_path: prove_task.receipt_path.clone(),
             ..Default::default()
-        };
-        agg_task.set_out_path(prove_dir);
-        agg_task
+        }
     }
 
     pub fn init_from_two_prove_task(
@@ -165,6 +198,8 @@ impl AggTask {
             proof_id: left.proof_id.clone(),
             input1: AggInput::from_prove_task(left),
             input2: AggInput::from_prove_task(right),
+            left: Some(left.task_id.clone()),
+            right: Some(right.task_id.clone()),
             ..Default::default()
         };
         agg_task.set_out_path(prove_dir);
@@ -188,14 +223,10 @@ impl AggTask {
             proof_id: left.proof_id.clone(),
             input1: left.to_agg_input(),
             input2: right.to_agg_input(),
+            left: Some(left.task_id.clone()),
+            right: Some(right.task_id.clone()),
             ..Default::default()
         };
-        if !left.from_prove {
-            agg_task.left = Some(left.task_id.clone());
-        }
-        if !right.from_prove {
-            agg_task.right = Some(right.task_id.clone());
-        }
         agg_task.set_out_path(prove_dir);
         agg_task
     }
@@ -226,19 +257,22 @@ mod tests {
             file_no: 1,
             ..Default::default()
         };
-        let agg_task = crate::tasks::AggTask::init_from_single_prove_task(&prove_task, "/test");
+        let agg_task = crate::tasks::AggTask::init_from_single_prove_task(&prove_task);
         assert!(agg_task.state == TASK_STATE_SUCCESS);
         assert!(agg_task.file_key == format!("{}", prove_task.file_no));
+        assert!(agg_task.task_id == prove_task.task_id);
     }
 
     #[test]
     fn test_init_from_two_prove_task() {
         let left_prove_task = ProveTask {
             file_no: 1,
+            task_id: "prove_1".to_string(),
             ..Default::default()
         };
         let right_prove_task = ProveTask {
             file_no: 2,
+            task_id: "prove_2".to_string(),
             ..Default::default()
         };
         let agg_task = crate::tasks::AggTask::init_from_two_prove_task(
@@ -249,6 +283,8 @@ mod tests {
         );
         assert!(agg_task.state == TASK_STATE_UNPROCESSED);
         assert!(agg_task.file_key == format!("agg{}", 1));
+        assert_eq!(agg_task.left.as_deref(), Some("prove_1"));
+        assert_eq!(agg_task.right.as_deref(), Some("prove_2"));
     }
 
     #[test]
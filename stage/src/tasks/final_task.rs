@@ -11,6 +11,14 @@ pub struct FinalTask {
     pub start_ts: u64,
     pub finish_ts: u64,
     pub node_info: String,
+    #[serde(default)]
+    pub errmsg: String,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub retry_at: u64,
+    #[serde(default)]
+    pub excluded_nodes: Vec<String>,
 }
 
 impl Clone for FinalTask {
@@ -24,6 +32,10 @@ impl Clone for FinalTask {
             start_ts: self.start_ts,
             finish_ts: self.finish_ts,
             node_info: self.node_info.clone(),
+            errmsg: self.errmsg.clone(),
+            attempts: self.attempts,
+            retry_at: self.retry_at,
+            excluded_nodes: self.excluded_nodes.clone(),
         }
     }
 }
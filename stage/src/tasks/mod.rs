@@ -19,16 +19,32 @@ pub use agg_task::AggTask;
 pub mod final_task;
 pub use final_task::FinalTask;
 
+pub mod wrap_task;
+pub use wrap_task::WrapTask;
+
 pub const TASK_ITYPE_SPLIT: i32 = 1;
 pub const TASK_ITYPE_PROVE: i32 = 2;
 pub const TASK_ITYPE_AGG: i32 = 3;
 pub const TASK_ITYPE_AGGALL: i32 = 4;
 pub const TASK_ITYPE_FINAL: i32 = 5;
+pub const TASK_ITYPE_WRAP: i32 = 6;
+
+/// A prover node was acquired for a prove or agg task, sent before its result
+#[derive(Debug, Clone, Default)]
+pub struct RunningTask {
+    pub itype: i32,
+    pub task_id: String,
+    pub node_info: String,
+    pub start_ts: u64,
+    pub speculative: bool,
+}
 
 pub enum Task {
     Split(SplitTask),
     Prove(ProveTask),
     Agg(AggTask),
     AggAll(AggAllTask),
+    Wrap(WrapTask),
     Final(FinalTask),
+    Running(RunningTask),
 }
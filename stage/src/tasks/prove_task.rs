@@ -16,6 +16,17 @@ pub struct ProveTask {
     pub start_ts: u64,
     pub finish_ts: u64,
     pub node_info: String,
+    #[serde(default)]
+    pub errmsg: String,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub retry_at: u64,
+    #[serde(default)]
+    pub excluded_nodes: Vec<String>,
+    // a duplicate of the task is running on another node
+    #[serde(default)]
+    pub speculative: bool,
 }
 
 impl Clone for ProveTask {
@@ -34,6 +45,11 @@ impl Clone for ProveTask {
             start_ts: self.start_ts,
             finish_ts: self.finish_ts,
             node_info: self.node_info.clone(),
+            errmsg: self.errmsg.clone(),
+            attempts: self.attempts,
+            retry_at: self.retry_at,
+            excluded_nodes: self.excluded_nodes.clone(),
+            speculative: self.speculative,
         }
     }
 }
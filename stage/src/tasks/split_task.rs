@@ -18,6 +18,14 @@ pub struct SplitTask {
     pub start_ts: u64,
     pub finish_ts: u64,
     pub node_info: String,
+    #[serde(default)]
+    pub errmsg: String,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub retry_at: u64,
+    #[serde(default)]
+    pub excluded_nodes: Vec<String>,
     pub total_steps: u64,
     pub recepit_inputs_path: String,
 }
@@ -40,6 +48,10 @@ impl Clone for SplitTask {
             start_ts: self.start_ts,
             finish_ts: self.finish_ts,
             node_info: self.node_info.clone(),
+            errmsg: self.errmsg.clone(),
+            attempts: self.attempts,
+            retry_at: self.retry_at,
+            excluded_nodes: self.excluded_nodes.clone(),
             total_steps: self.total_steps,
             recepit_inputs_path: self.recepit_inputs_path.clone(),
         }
@@ -0,0 +1,47 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct WrapTask {
+    pub task_id: String,
+    pub state: u32,
+    pub base_dir: String,
+    pub block_no: u64,
+    pub seg_size: u32,
+    pub proof_id: String,
+    pub agg_receipt_path: String,
+    pub output_dir: String,
+    pub start_ts: u64,
+    pub finish_ts: u64,
+    pub node_info: String,
+    #[serde(default)]
+    pub errmsg: String,
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub retry_at: u64,
+    #[serde(default)]
+    pub excluded_nodes: Vec<String>,
+}
+
+impl Clone for WrapTask {
+    fn clone(&self) -> Self {
+        WrapTask {
+            task_id: self.task_id.clone(),
+            state: self.state,
+            base_dir: self.base_dir.clone(),
+            block_no: self.block_no,
+            seg_size: self.seg_size,
+            proof_id: self.proof_id.clone(),
+            agg_receipt_path: self.agg_receipt_path.clone(),
+            output_dir: self.output_dir.clone(),
+            start_ts: self.start_ts,
+            finish_ts: self.finish_ts,
+            node_info: self.node_info.clone(),
+            errmsg: self.errmsg.clone(),
+            attempts: self.attempts,
+            retry_at: self.retry_at,
+            excluded_nodes: self.excluded_nodes.clone(),
+        }
+    }
+}